@@ -5,6 +5,60 @@
 
 VIEW_BASE_URL = "/";
 
+/** Client side encryption
+ * - Content is encrypted with AES-GCM using a random 256-bit key that
+ *   only lives in the link's fragment (`/<paste-key>#<key>`), which
+ *   browsers never send to the server.
+ * - Keys are base64url encoded without padding.
+ * - The posted body is base64(iv || ciphertext) with a 12-byte iv, and is
+ *   created with `/new?client_encrypted=true`. API clients can do the same.
+ */
+function bytesToBase64(bytes) {
+    var s = "";
+    for (var i = 0; i < bytes.length; i++) {
+        s += String.fromCharCode(bytes[i]);
+    }
+    return btoa(s);
+}
+
+function base64ToBytes(b64) {
+    b64 = b64.replace(/-/g, "+").replace(/_/g, "/");
+    while (b64.length % 4) { b64 += "="; }
+    var s = atob(b64);
+    var bytes = new Uint8Array(s.length);
+    for (var i = 0; i < s.length; i++) {
+        bytes[i] = s.charCodeAt(i);
+    }
+    return bytes;
+}
+
+function clientEncrypt(text) {
+    var rawKey = crypto.getRandomValues(new Uint8Array(32));
+    var iv = crypto.getRandomValues(new Uint8Array(12));
+    return crypto.subtle.importKey("raw", rawKey, "AES-GCM", false, ["encrypt"])
+        .then(function(key) {
+            return crypto.subtle.encrypt({name: "AES-GCM", iv: iv}, key, new TextEncoder().encode(text));
+        })
+        .then(function(ciphertext) {
+            var body = new Uint8Array(iv.length + ciphertext.byteLength);
+            body.set(iv);
+            body.set(new Uint8Array(ciphertext), iv.length);
+            var key = bytesToBase64(rawKey).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+            return {key: key, body: bytesToBase64(body)};
+        });
+}
+
+function clientDecrypt(body, key) {
+    var bytes = base64ToBytes(body.trim());
+    return crypto.subtle.importKey("raw", base64ToBytes(key.trim()), "AES-GCM", false, ["decrypt"])
+        .then(function(key) {
+            return crypto.subtle.decrypt({name: "AES-GCM", iv: bytes.slice(0, 12)}, key, bytes.slice(12));
+        })
+        .then(function(plaintext) {
+            return new TextDecoder().decode(plaintext);
+        });
+}
+
 document.addEventListener("DOMContentLoaded", function() {
     var save   = document.getElementById("save-paste");     // save-paste button/element
     var edit   = document.getElementById("edit-paste");     // edit-paste button/element
@@ -13,14 +67,19 @@ document.addEventListener("DOMContentLoaded", function() {
     var typeSelector = document.getElementById("type-selector");    // select ace-editor mode
     var encryptionKeyInput = document.getElementById("encryption-key");    // select encryption-key password
     var pasteId = document.getElementById("paste-id");              // existing paste-id
+    var jsonUrl = document.getElementById("json-url");              // existing paste as json
     var copyLink = document.getElementById("copy-link");                   // share button
     var copyCode = document.getElementById("copy-code");                   // share button
     var encryptionKeyRequired = !!document.getElementById("encryption-key-required");
+    var clientEncrypted = !!document.getElementById("client-encrypted");
+    var clientEncryptInput = document.getElementById("client-encrypt");    // encrypt in the browser
+    var clientEncryptLabel = document.getElementById("client-encrypt-label");
+    var ciphertext = document.getElementById("ciphertext");         // client encrypted content
     var decryptionKeyInput = document.getElementById("decryption-key");   // decryption pass
     var decryptPaste = document.getElementById("decrypt-paste");     // decrypt button
     var editorElem = document.getElementById("editor");
 
-    if (encryptionKeyRequired) {
+    if (encryptionKeyRequired || clientEncrypted) {
         edit.style.display = "none";
     }
 
@@ -34,6 +93,14 @@ document.addEventListener("DOMContentLoaded", function() {
         editor.session.setMode('ace/mode/'+value);
     });
 
+    function showDecrypted(content) {
+        editor.setValue(content, -1);
+        decryptionKeyInput.style.display = "none";
+        decryptPaste.style.display = "none";
+        edit.style.display = "";
+        editorElem.style.top = "70";
+    }
+
     /** Decrypt content
      */
     var didDecrypt = false;
@@ -41,14 +108,27 @@ document.addEventListener("DOMContentLoaded", function() {
         if (didDecrypt) { return; }
         didDecrypt = true;
         var _decKey = decryptionKeyInput.value;
-        var _pasteKey = pasteId.innerText;
+
+        if (clientEncrypted) {
+            clientDecrypt(ciphertext.innerText, _decKey).then(showDecrypted, function() {
+                didDecrypt = false;
+                alert("Error decrypting paste.");
+            });
+            return;
+        }
 
         var http = new XMLHttpRequest();
-        var url  = "/json/"+_pasteKey;
+        // through the share link when the paste was reached by one
+        var url  = jsonUrl.value;
         http.open("GET", url, true);
         http.setRequestHeader("x-upaste-encryption-key", _decKey);
         http.onreadystatechange = function() {
             if (http.readyState !== XMLHttpRequest.DONE) { return; }
+            if (http.status == 429) {
+                didDecrypt = false;
+                alert("Too many failed attempts, try again in "+http.getResponseHeader("Retry-After")+"s.");
+                return;
+            }
             if (http.status != 200) {
                 didDecrypt = false;
                 alert("Error decrypting paste.");
@@ -56,14 +136,9 @@ document.addEventListener("DOMContentLoaded", function() {
             }
             var resp = JSON.parse(http.responseText);
             if (resp.paste) {
-                var content = resp.paste.content;
-                editor.setValue(content);
+                showDecrypted(resp.paste.content);
                 editor.session.setMode('ace/mode/'+resp.paste.content_type);
                 typeSelector.value = resp.paste.content_type;
-                decryptionKeyInput.style.display = "none";
-                decryptPaste.style.display = "none";
-                edit.style.display = "";
-                editorElem.style.top = "70";
 
                 for (var i = 0, len = typeSelector.length; i < len; i++) {
                     if (typeSelector[i].value.trim() === resp.paste.content_type.trim()) {
@@ -91,6 +166,11 @@ document.addEventListener("DOMContentLoaded", function() {
             doDecrypt();
         });
     }
+    // the key of a client encrypted paste is normally in the link
+    if (clientEncrypted && window.location.hash.length > 1) {
+        decryptionKeyInput.value = window.location.hash.slice(1);
+        doDecrypt();
+    }
 
     /** Save content
      * - When the save button is present (which it should always be, might just be hidden),
@@ -105,12 +185,23 @@ document.addEventListener("DOMContentLoaded", function() {
         if (!contentType) { contentType = "text" }
         var encryptionKey = encryptionKeyInput.value;
         var hasKey = !(encryptionKey === "" || encryptionKey === null || encryptionKey === undefined);
-
+        if (clientEncryptInput.checked) {
+            clientEncrypt(content).then(function(enc) {
+                post(enc.body, contentType, null, "&client_encrypted=true", "#"+enc.key);
+            }, function() {
+                didSave = false;
+                alert("Error encrypting paste.");
+            });
+            return;
+        }
+        post(content, contentType, hasKey ? encryptionKey : null, "", "");
+    }
+    function post(content, contentType, encryptionKey, extraParams, fragment) {
         var http = new XMLHttpRequest();
-        var url  = "/new?type="+contentType;
+        var url  = "/new?type="+contentType+extraParams;
         http.open("POST", url, true);
         http.setRequestHeader("Content-Type", "text/plain");
-        if (hasKey) {
+        if (encryptionKey) {
             http.setRequestHeader("x-upaste-encryption-key", encryptionKey);
         }
         http.onreadystatechange = function() {
@@ -122,7 +213,7 @@ document.addEventListener("DOMContentLoaded", function() {
             }
             var resp = JSON.parse(http.responseText);
             if (resp.key) {
-                window.location.href = VIEW_BASE_URL+resp.key;
+                window.location.href = VIEW_BASE_URL+resp.key+fragment;
             }
             else {
                 didSave = false;
@@ -143,6 +234,12 @@ document.addEventListener("DOMContentLoaded", function() {
             doSave();
         });
     }
+    // a client encrypted paste never sees the server-side encryption key
+    if (clientEncryptInput) {
+        clientEncryptInput.addEventListener("change", function() {
+            encryptionKeyInput.style.display = clientEncryptInput.checked ? "none" : "";
+        });
+    }
 
     /** Edit existing content
      * - When the edit button is present:
@@ -155,8 +252,12 @@ document.addEventListener("DOMContentLoaded", function() {
         edit.addEventListener("click", function(){
             edit.style.display = "none";
             save.style.display = "";
-            var key = document.getElementById("paste-id");
-            key.innerText = '';
+            // there's no key to hide when reached through a share link
+            if (pasteId) {
+                pasteId.innerText = '';
+                copyLink.style.cssText = "display: none;";
+                copyCode.style.cssText = "display: none;";
+            }
             editor.setReadOnly(false);
 
             // show the type selector and encryption password fields
@@ -169,11 +270,11 @@ document.addEventListener("DOMContentLoaded", function() {
                 }
             }
 
-            encryptionKeyInput.style.display = "";
             encryptionKeyInput.value = "";
-
-            copyLink.style.cssText = "display: none;";
-            copyCode.style.cssText = "display: none;";
+            // keep edits of client encrypted pastes client encrypted
+            clientEncryptInput.checked = clientEncrypted;
+            clientEncryptLabel.style.display = "";
+            encryptionKeyInput.style.display = clientEncrypted ? "none" : "";
         });
     }
 
@@ -183,7 +284,8 @@ document.addEventListener("DOMContentLoaded", function() {
         var copyLinkText = copyLink.innerText;
         var copyCodeText = copyCode.innerText;
         copyLink.addEventListener("click", function() {
-            navigator.clipboard.writeText(window.location.protocol + '//' + window.location.hostname + VIEW_BASE_URL + pasteId.innerText.trim());
+            // includes the fragment holding a client encrypted paste's key
+            navigator.clipboard.writeText(window.location.protocol + '//' + window.location.hostname + VIEW_BASE_URL + pasteId.innerText.trim() + window.location.hash);
             copyLink.innerText = copyLinkText + " ✓";
             copyCode.innerText = copyCodeText;
         });
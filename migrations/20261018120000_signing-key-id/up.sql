@@ -0,0 +1,3 @@
+-- null for pastes signed before key ids were tracked
+alter table pastes
+    add column signing_key_id text;
@@ -0,0 +1,12 @@
+begin;
+
+-- `content` is encrypted at rest with the master key `rest_key_id`,
+-- null for pastes written before encryption at rest
+alter table pastes
+    add column rest_nonce text;
+alter table pastes
+    add column rest_salt text;
+alter table pastes
+    add column rest_key_id text;
+
+commit;
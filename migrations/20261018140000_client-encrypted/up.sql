@@ -0,0 +1,3 @@
+-- content was encrypted by the client and is opaque to the server
+alter table pastes
+    add column client_encrypted integer NOT NULL DEFAULT 0;
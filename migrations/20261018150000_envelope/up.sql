@@ -0,0 +1,10 @@
+begin;
+
+-- json `crypto::Envelope`s describing how the user key and at-rest
+-- layers were encrypted, null for rows written before envelopes
+alter table pastes
+    add column envelope text;
+alter table pastes
+    add column rest_envelope text;
+
+commit;
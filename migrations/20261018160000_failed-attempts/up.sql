@@ -0,0 +1,12 @@
+begin;
+
+-- consecutive failed decryptions, reset on success
+alter table pastes
+    add column failed_attempts integer NOT NULL DEFAULT 0;
+alter table pastes
+    add column last_failed_attempt unsigned big int;
+-- delete the paste once `failed_attempts` reaches this, null to keep it
+alter table pastes
+    add column max_failed_attempts integer;
+
+commit;
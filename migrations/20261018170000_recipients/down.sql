@@ -0,0 +1,3 @@
+drop trigger paste_recipients_cleanup;
+drop table paste_recipients;
+drop table recipients;
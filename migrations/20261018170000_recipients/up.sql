@@ -0,0 +1,31 @@
+begin;
+
+-- named X25519 public keys pastes can be encrypted to
+create table recipients (
+    id integer primary key autoincrement,
+    name text unique not null,
+    public_key blob not null,
+    date_created unsigned big int not null
+);
+
+-- a paste's data key wrapped for each of its recipients
+create table paste_recipients (
+    id integer primary key autoincrement,
+    paste_id integer not null,
+    public_key blob not null,
+    ephemeral_public_key blob not null,
+    nonce blob not null,
+    wrapped_key blob not null
+);
+create index paste_recipients_paste_id on paste_recipients (paste_id);
+
+-- pastes are deleted from several places and foreign keys aren't enforced
+create trigger paste_recipients_cleanup after delete on pastes
+begin
+    delete from paste_recipients where paste_id = old.id;
+end;
+
+alter table pastes
+    add column recipient_encrypted integer NOT NULL DEFAULT 0;
+
+commit;
@@ -0,0 +1,7 @@
+begin;
+
+-- Ed25519 signature over `crypto::attestation`, by the key derived from `signing_key_id`
+alter table pastes
+    add column ed25519_signature blob;
+
+commit;
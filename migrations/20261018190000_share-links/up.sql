@@ -0,0 +1,7 @@
+begin;
+
+-- bumped to revoke every share link minted for the paste
+alter table pastes
+    add column share_generation integer NOT NULL DEFAULT 0;
+
+commit;
@@ -0,0 +1,7 @@
+begin;
+
+-- created with a long unguessable key, never indexed or listed
+alter table pastes
+    add column private integer NOT NULL DEFAULT 0;
+
+commit;
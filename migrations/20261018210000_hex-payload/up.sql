@@ -0,0 +1,17 @@
+begin;
+
+-- user ciphertext under the at-rest layer is hex encoded, as written
+-- before it was stored as raw bytes, see `Paste::unhex_payloads`
+alter table pastes
+    add column hex_payload integer NOT NULL DEFAULT 0;
+update pastes set hex_payload = 1
+    where nonce is not null and rest_key_id is not null
+    and (rest_envelope is null or json_extract(rest_envelope, '$.v') < 2);
+-- envelope version 2 only meant the payload wasn't hex, and
+-- was also used for the user layer of recipient encrypted pastes
+update pastes set rest_envelope = json_set(rest_envelope, '$.v', 1)
+    where json_extract(rest_envelope, '$.v') = 2;
+update pastes set envelope = json_set(envelope, '$.v', 1)
+    where json_extract(envelope, '$.v') = 2;
+
+commit;
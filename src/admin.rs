@@ -9,6 +9,8 @@ use chrono::{DateTime, NaiveDate, TimeZone, Utc};
 use clap::ArgMatches;
 use time::Duration;
 
+use crate::config;
+use crate::crypto;
 use crate::errors::*;
 use crate::models;
 use crate::service;
@@ -30,11 +32,13 @@ fn confirm(msg: &str) -> Result<()> {
 fn delete_pastes_before<T: AsRef<path::Path>>(
     date: DateTime<Utc>,
     no_confirm: bool,
+    config: &config::Config,
     database_path: T,
 ) -> Result<()> {
     let conn = service::establish_connection(database_path.as_ref());
 
-    let count = models::Paste::count_outdated(&conn, &date)?;
+    let now = chrono::Utc::now();
+    let count = models::Paste::count_outdated(&conn, &date, &now, config.max_ttl_seconds)?;
     println!(
         "** Found {} pastes that weren't viewed since {} **",
         count, date
@@ -50,12 +54,132 @@ fn delete_pastes_before<T: AsRef<path::Path>>(
         }
     }
 
-    let n_deleted = models::Paste::delete_outdated(&conn, &date, &chrono::Utc::now())?;
+    let n_deleted =
+        models::Paste::delete_outdated(&conn, &date, &chrono::Utc::now(), config.max_ttl_seconds)?;
     println!("** {} pastes deleted", n_deleted);
     Ok(())
 }
 
-pub fn handle(matches: &ArgMatches) -> Result<()> {
+/// Database path from `--db-path`, or the migrant config
+fn database_path(matches: &ArgMatches) -> Result<path::PathBuf> {
+    Ok(match matches.value_of("database") {
+        Some(p) => path::PathBuf::from(p),
+        None => service::migrant_config()?
+            .database_path()
+            .chain_err(|| "No config file found")?,
+    })
+}
+
+/// Generate a random hex encoded key of `n_bytes`, written to `out`
+/// (readable only by the owner) or printed
+fn keygen(n_bytes: usize, out: Option<&path::Path>) -> Result<()> {
+    if n_bytes * 2 < config::MIN_KEY_BYTES {
+        bail_fmt!(
+            ErrorKind::BadRequest,
+            "keys must be at least {} bytes ({} hex characters)",
+            config::MIN_KEY_BYTES / 2,
+            config::MIN_KEY_BYTES
+        );
+    }
+    let key = hex::encode(crypto::rand_bytes(n_bytes)?);
+    match out {
+        Some(out) => {
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            let mut f = options
+                .open(out)
+                .chain_err(|| format!("Error creating key file {}", out.display()))?;
+            writeln!(f, "{}", key)?;
+            println!("** Key written to {} **", out.display());
+        }
+        None => println!("{}", key),
+    }
+    Ok(())
+}
+
+/// Re-sign pastes with the current signing key so retired keys can be dropped
+fn resign(config: &config::Config, database_path: &path::Path) -> Result<()> {
+    let mut conn = service::establish_connection(database_path);
+    let stats = models::Paste::resign_all(&mut conn, config)?;
+    println!(
+        "** {} pastes re-signed with key `{}` **",
+        stats.resigned, config.signing_key_id
+    );
+    if stats.skipped_encrypted > 0 {
+        println!(
+            "** {} encrypted pastes skipped, keep their signing keys in `retired_signing_keys` until they expire **",
+            stats.skipped_encrypted
+        );
+    }
+    if stats.invalid > 0 {
+        println!(
+            "** {} pastes with invalid signatures skipped, see logs **",
+            stats.invalid
+        );
+    }
+    Ok(())
+}
+
+/// Encrypt plaintext pastes at rest and move pastes off retired master keys
+fn reencrypt(config: &config::Config, database_path: &path::Path) -> Result<()> {
+    let mut conn = service::establish_connection(database_path);
+    let n = models::Paste::reencrypt_all(&mut conn, config)?;
+    println!(
+        "** {} pastes encrypted with key `{}` **",
+        n, config.encryption_key_id
+    );
+    Ok(())
+}
+
+/// Add, remove, and list the recipients pastes can be encrypted to
+fn recipients(matches: &ArgMatches) -> Result<()> {
+    if matches.subcommand_matches("keygen").is_some() {
+        let (secret, public_key) = crypto::x25519_keypair()?;
+        println!("secret key: {}", hex::encode(secret));
+        println!("public key: {}", hex::encode(public_key));
+        return Ok(());
+    }
+    let conn = service::establish_connection(database_path(matches)?);
+    match matches.subcommand() {
+        ("add", Some(matches)) => {
+            let name = matches.value_of("name").expect("name is required");
+            let public_key = hex::decode(
+                matches
+                    .value_of("public-key")
+                    .expect("public-key is required"),
+            )
+            .chain_err(|| "public keys must be hex encoded")?;
+            models::Recipient::create(&conn, name, &public_key)?;
+            println!("** Added recipient `{}` **", name);
+        }
+        ("remove", Some(matches)) => {
+            let name = matches.value_of("name").expect("name is required");
+            if !models::Recipient::delete(&conn, name)? {
+                bail_fmt!(ErrorKind::DoesNotExist, "no recipient named `{}`", name);
+            }
+            println!("** Removed recipient `{}` **", name);
+        }
+        ("list", _) => {
+            for recipient in models::Recipient::all(&conn)? {
+                println!(
+                    "{}\t{}\t{}",
+                    recipient.name,
+                    hex::encode(&recipient.public_key),
+                    recipient.date_created.format("%Y-%m-%d")
+                );
+            }
+        }
+        _ => println!("see `--help`"),
+    }
+    Ok(())
+}
+
+pub fn handle(matches: &ArgMatches, config_path: Option<&path::Path>) -> Result<()> {
     if let Some(db_matches) = matches.subcommand_matches("database") {
         let config = service::migrant_config()?;
         let was_setup = config.setup()?;
@@ -75,13 +199,27 @@ pub fn handle(matches: &ArgMatches) -> Result<()> {
                     .direction(migrant_lib::Direction::Up)
                     .all(true)
                     .apply();
-                if let Err(ref err) = res {
-                    if err.is_migration_complete() {
+                match res {
+                    Err(ref err) if err.is_migration_complete() => {
                         println!("Database is up-to-date!");
-                        return Ok(());
                     }
+                    res => res?,
+                }
+                let mut conn = service::establish_connection(
+                    config
+                        .database_path()
+                        .chain_err(|| "No config file found")?,
+                );
+                let n = models::Paste::unhex_all(&mut conn)?;
+                if n > 0 {
+                    println!("** Converted {} pastes from hex to binary columns **", n);
+                }
+                // only needs the master keys when there's something to convert
+                if models::Paste::has_hex_payloads(&conn)? {
+                    let config = config::Config::load(config_path)?;
+                    let n = models::Paste::unhex_payloads(&mut conn, &config)?;
+                    println!("** Converted {} pastes from hex to binary ciphertext **", n);
                 }
-                res?;
                 return Ok(());
             }
             _ => println!("see `--help`"),
@@ -92,12 +230,8 @@ pub fn handle(matches: &ArgMatches) -> Result<()> {
 
     if let Some(matches) = matches.subcommand_matches("clean-before") {
         let no_confirm = matches.is_present("no-confirm");
-        let database_path = match matches.value_of("database") {
-            Some(p) => path::PathBuf::from(p),
-            None => service::migrant_config()?
-                .database_path()
-                .chain_err(|| "No config file found")?,
-        };
+        let config = config::Config::load(config_path)?;
+        let database_path = database_path(matches)?;
         if let Some(v) = matches.value_of("date") {
             let date = {
                 let date = NaiveDate::parse_from_str(v, "%Y-%m-%d")
@@ -105,18 +239,41 @@ pub fn handle(matches: &ArgMatches) -> Result<()> {
                 let date = Utc.from_utc_date(&date);
                 date.and_hms(0, 0, 0)
             };
-            delete_pastes_before(date, no_confirm, &database_path)?;
+            delete_pastes_before(date, no_confirm, &config, &database_path)?;
             return Ok(());
         }
 
         if let Some(v) = matches.value_of("days") {
             let n = v.parse::<u32>()?;
             let date = Utc::now() - Duration::seconds(60 * 60 * 24 * n as i64);
-            delete_pastes_before(date, no_confirm, &database_path)?;
+            delete_pastes_before(date, no_confirm, &config, &database_path)?;
             return Ok(());
         }
     }
 
+    if let Some(matches) = matches.subcommand_matches("resign") {
+        let config = config::Config::load(config_path)?;
+        resign(&config, &database_path(matches)?)?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("reencrypt") {
+        let config = config::Config::load(config_path)?;
+        reencrypt(&config, &database_path(matches)?)?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("recipients") {
+        recipients(matches)?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("keygen") {
+        let n_bytes = matches.value_of("bytes").unwrap_or("32").parse::<usize>()?;
+        keygen(n_bytes, matches.value_of("out").map(path::Path::new))?;
+        return Ok(());
+    }
+
     println!("See: upaste admin --help");
     Ok(())
 }
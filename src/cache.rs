@@ -0,0 +1,218 @@
+/*!
+In-memory cache of recently viewed pastes
+
+Only unencrypted pastes are ever cached. Entries must be invalidated
+whenever the backing row is deleted or expires. Rows changed outside
+the server (`admin` commands) are picked up once their entry's ttl runs out.
+*/
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+use crate::models::Paste;
+
+struct Entry {
+    paste: Paste,
+    tick: u64,
+    cached: Instant,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    // access tick -> paste key, oldest first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+impl Inner {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+/// Bounded least-recently-used cache of pastes, keyed by `Paste::key`
+pub struct PasteCache {
+    capacity: usize,
+    ttl: Duration,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub size: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl PasteCache {
+    /// Create a new cache holding at most `capacity` pastes for up to `ttl`.
+    /// A capacity of zero disables caching.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            inner: Mutex::new(Inner::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, Inner>> {
+        self.inner.lock().map_err(|e| {
+            format_err!(ErrorKind::SyncPoison, "paste cache lock poisoned: {}", e).into()
+        })
+    }
+
+    /// Return a copy of the cached paste, marking it as most recently used.
+    /// Entries older than the cache's ttl are dropped instead.
+    pub fn get(&self, key: &str) -> Result<Option<Paste>> {
+        if self.capacity == 0 {
+            return Ok(None);
+        }
+        let mut inner = self.lock()?;
+        let tick = inner.next_tick();
+        let inner = &mut *inner;
+        let stale =
+            matches!(inner.entries.get(key), Some(entry) if entry.cached.elapsed() >= self.ttl);
+        if stale {
+            if let Some(entry) = inner.entries.remove(key) {
+                inner.order.remove(&entry.tick);
+            }
+        }
+        let found = match inner.entries.get_mut(key) {
+            Some(entry) => {
+                inner.order.remove(&entry.tick);
+                entry.tick = tick;
+                inner.order.insert(tick, key.to_string());
+                Some(entry.paste.clone())
+            }
+            None => None,
+        };
+        if found.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(found)
+    }
+
+    /// Cache an unencrypted paste, evicting the least recently used
+    /// entry if the cache is full. Encrypted pastes are ignored.
+    pub fn insert(&self, paste: &Paste) -> Result<()> {
+        if self.capacity == 0 || paste.nonce.is_some() {
+            return Ok(());
+        }
+        let mut inner = self.lock()?;
+        let tick = inner.next_tick();
+        if let Some(old) = inner.entries.remove(&paste.key) {
+            inner.order.remove(&old.tick);
+        }
+        while inner.entries.len() >= self.capacity {
+            let oldest = match inner.order.keys().next() {
+                Some(t) => *t,
+                None => break,
+            };
+            if let Some(key) = inner.order.remove(&oldest) {
+                inner.entries.remove(&key);
+            }
+        }
+        inner.order.insert(tick, paste.key.clone());
+        inner.entries.insert(
+            paste.key.clone(),
+            Entry {
+                paste: paste.clone(),
+                tick,
+                cached: Instant::now(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Drop a paste from the cache
+    pub fn remove(&self, key: &str) -> Result<()> {
+        let mut inner = self.lock()?;
+        if let Some(entry) = inner.entries.remove(key) {
+            inner.order.remove(&entry.tick);
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Result<CacheStats> {
+        let size = self.lock()?.entries.len();
+        Ok(CacheStats {
+            capacity: self.capacity,
+            size,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached_keys(cache: &PasteCache, keys: &[&str]) -> Vec<bool> {
+        keys.iter()
+            .map(|key| cache.get(key).unwrap().is_some())
+            .collect()
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = PasteCache::new(2, Duration::from_secs(60));
+        cache.insert(&Paste::unsaved("a", "a")).unwrap();
+        cache.insert(&Paste::unsaved("b", "b")).unwrap();
+        // reading `a` makes `b` the oldest
+        assert!(cache.get("a").unwrap().is_some());
+        cache.insert(&Paste::unsaved("c", "c")).unwrap();
+        assert_eq!(cached_keys(&cache, &["a", "b", "c"]), [true, false, true]);
+
+        // re-inserting refreshes an entry instead of evicting
+        cache.insert(&Paste::unsaved("a", "new")).unwrap();
+        assert_eq!(cache.get("a").unwrap().unwrap().content, "new");
+        cache.insert(&Paste::unsaved("d", "d")).unwrap();
+        assert_eq!(cached_keys(&cache, &["a", "c", "d"]), [true, false, true]);
+        assert_eq!(cache.stats().unwrap().size, 2);
+    }
+
+    #[test]
+    fn hits_and_misses() {
+        let cache = PasteCache::new(2, Duration::from_secs(60));
+        assert!(cache.get("a").unwrap().is_none());
+        cache.insert(&Paste::unsaved("a", "a")).unwrap();
+        assert!(cache.get("a").unwrap().is_some());
+        assert!(cache.get("a").unwrap().is_some());
+        cache.remove("a").unwrap();
+        assert!(cache.get("a").unwrap().is_none());
+
+        // encrypted pastes are never cached
+        let mut encrypted = Paste::unsaved("b", "b");
+        encrypted.nonce = Some(vec![0; 12]);
+        cache.insert(&encrypted).unwrap();
+        assert!(cache.get("b").unwrap().is_none());
+
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.size), (2, 3, 0));
+    }
+
+    #[test]
+    fn expires_after_ttl() {
+        let cache = PasteCache::new(2, Duration::from_millis(0));
+        cache.insert(&Paste::unsaved("a", "a")).unwrap();
+        assert!(cache.get("a").unwrap().is_none());
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.size), (0, 1, 0));
+
+        // a disabled cache keeps nothing
+        let cache = PasteCache::new(0, Duration::from_secs(60));
+        cache.insert(&Paste::unsaved("a", "a")).unwrap();
+        assert!(cache.get("a").unwrap().is_none());
+    }
+}
@@ -0,0 +1,723 @@
+/*!
+Configuration
+
+Values are layered, each overriding the last:
+ - defaults
+ - an optional TOML config file, from `--config` or `UPASTE_CONFIG`
+ - environment variables
+ - command line flags
+*/
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::ArgMatches;
+
+use crate::errors::*;
+
+/// Where a config value came from
+#[derive(Debug, Clone)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    Env(&'static str),
+    Flag,
+}
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "file {}", path.display()),
+            Source::Env(var) => write!(f, "env {}", var),
+            Source::Flag => write!(f, "flag"),
+        }
+    }
+}
+
+struct Field {
+    name: &'static str,
+    env: &'static str,
+    default: &'static str,
+    // never displayed
+    secret: bool,
+}
+
+macro_rules! field {
+    ($name:expr, $env:expr, $default:expr) => {
+        Field {
+            name: $name,
+            env: $env,
+            default: $default,
+            secret: false,
+        }
+    };
+    ($name:expr, $env:expr, $default:expr, secret) => {
+        Field {
+            name: $name,
+            env: $env,
+            default: $default,
+            secret: true,
+        }
+    };
+}
+
+/// Public development key, refused in `prod` mode
+pub static DEFAULT_KEY: &str = "01234567890123456789012345678901";
+
+/// Minimum key length accepted in `prod` mode
+pub const MIN_KEY_BYTES: usize = 32;
+
+static FIELDS: &[Field] = &[
+    field!("mode", "MODE", "prod"),
+    field!("host", "HOST", "localhost"),
+    field!("port", "PORT", "3003"),
+    field!("log_level", "LOG_LEVEL", "INFO"),
+    field!("log_format", "LOG_FORMAT", "text"),
+    field!("encryption_key", "ENCRYPTION_KEY", DEFAULT_KEY, secret),
+    field!("encryption_key_id", "ENCRYPTION_KEY_ID", "default"),
+    // `id:key` pairs separated by commas or whitespace
+    field!(
+        "retired_encryption_keys",
+        "RETIRED_ENCRYPTION_KEYS",
+        "",
+        secret
+    ),
+    field!("signing_key", "SIGNING_KEY", DEFAULT_KEY, secret),
+    field!("signing_key_id", "SIGNING_KEY_ID", "default"),
+    // `id:key` pairs separated by commas or whitespace
+    field!("retired_signing_keys", "RETIRED_SIGNING_KEYS", "", secret),
+    // files to read keys from instead, empty when unset
+    field!("encryption_key_file", "ENCRYPTION_KEY_FILE", ""),
+    field!(
+        "retired_encryption_keys_file",
+        "RETIRED_ENCRYPTION_KEYS_FILE",
+        ""
+    ),
+    field!("signing_key_file", "SIGNING_KEY_FILE", ""),
+    field!("retired_signing_keys_file", "RETIRED_SIGNING_KEYS_FILE", ""),
+    field!("max_paste_bytes", "MAX_PASTE_BYTES", "1000000"),
+    // 60 * 60 * 24 * 30
+    field!("max_paste_age_seconds", "MAX_PASTE_AGE_SECONDS", "2592000"),
+    field!("max_ttl_seconds", "MAX_TTL_SECONDS", "2592000"),
+    field!("paste_cache_size", "PASTE_CACHE_SIZE", "256"),
+    field!("paste_cache_ttl_seconds", "PASTE_CACHE_TTL_SECONDS", "60"),
+    field!(
+        "view_flush_interval_seconds",
+        "VIEW_FLUSH_INTERVAL_SECONDS",
+        "5"
+    ),
+    field!("db_journal_mode", "DB_JOURNAL_MODE", "WAL"),
+    field!("db_synchronous", "DB_SYNCHRONOUS", "NORMAL"),
+    field!("db_busy_timeout_ms", "DB_BUSY_TIMEOUT_MS", "5000"),
+    field!("db_pool_size", "DB_POOL_SIZE", "10"),
+    field!(
+        "db_connection_timeout_seconds",
+        "DB_CONNECTION_TIMEOUT_SECONDS",
+        "5"
+    ),
+    // 100MB
+    field!("min_free_disk_bytes", "MIN_FREE_DISK_BYTES", "100000000"),
+    field!("shutdown_timeout_seconds", "SHUTDOWN_TIMEOUT_SECONDS", "4"),
+    field!("client_ip_header", "CLIENT_IP_HEADER", ""),
+    field!("decrypt_free_attempts", "DECRYPT_FREE_ATTEMPTS", "3"),
+    field!(
+        "decrypt_backoff_max_seconds",
+        "DECRYPT_BACKOFF_MAX_SECONDS",
+        "900"
+    ),
+    field!("key_miss_free_attempts", "KEY_MISS_FREE_ATTEMPTS", "20"),
+    field!(
+        "key_miss_backoff_max_seconds",
+        "KEY_MISS_BACKOFF_MAX_SECONDS",
+        "300"
+    ),
+    field!("kdf_workers", "KDF_WORKERS", "4"),
+    field!("kdf_queue_size", "KDF_QUEUE_SIZE", "64"),
+];
+
+fn find_field(name: &str) -> Option<&'static Field> {
+    FIELDS.iter().find(|f| f.name == name)
+}
+
+/// Resolve the config file path from the `--config` flag or `UPASTE_CONFIG`
+pub fn config_path(flag: Option<&str>) -> Option<PathBuf> {
+    flag.map(PathBuf::from)
+        .or_else(|| std::env::var_os("UPASTE_CONFIG").map(PathBuf::from))
+}
+
+/// Unvalidated config values and where they came from,
+/// in the same order as `FIELDS`
+#[derive(Clone)]
+pub struct RawConfig {
+    values: Vec<(String, Source)>,
+}
+
+impl RawConfig {
+    fn defaults() -> Self {
+        Self {
+            values: FIELDS
+                .iter()
+                .map(|f| (f.default.to_string(), Source::Default))
+                .collect(),
+        }
+    }
+
+    fn index(name: &str) -> Result<usize> {
+        FIELDS
+            .iter()
+            .position(|f| f.name == name)
+            .ok_or_else(|| format_err!(ErrorKind::InvalidConfig, "unknown field `{}`", name).into())
+    }
+
+    fn set(&mut self, name: &str, value: String, source: Source) -> Result<()> {
+        let i = Self::index(name)?;
+        self.values[i] = (value, source);
+        Ok(())
+    }
+
+    /// Override a value from a command line flag
+    pub fn set_flag(&mut self, name: &str, value: &str) -> Result<()> {
+        self.set(name, value.to_string(), Source::Flag)
+    }
+
+    fn merge_file(&mut self, path: &Path) -> Result<()> {
+        let mut s = String::new();
+        std::fs::File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut s))
+            .chain_err(|| format!("Error reading config file {}", path.display()))?;
+        let table = match s.parse::<toml::Value>() {
+            Ok(toml::Value::Table(table)) => table,
+            Ok(_) => bail_fmt!(
+                ErrorKind::InvalidConfig,
+                "{}: expected a table",
+                path.display()
+            ),
+            Err(e) => bail_fmt!(ErrorKind::InvalidConfig, "{}: {}", path.display(), e),
+        };
+        for (name, value) in table {
+            if find_field(&name).is_none() {
+                bail_fmt!(
+                    ErrorKind::InvalidConfig,
+                    "{}: unknown field `{}`",
+                    path.display(),
+                    name
+                );
+            }
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(n) => n.to_string(),
+                toml::Value::Float(n) => n.to_string(),
+                toml::Value::Boolean(b) => b.to_string(),
+                other => bail_fmt!(
+                    ErrorKind::InvalidConfig,
+                    "{}: `{}` must be a string or number, found {}",
+                    path.display(),
+                    name,
+                    other.type_str()
+                ),
+            };
+            self.set(&name, value, Source::File(path.to_path_buf()))?;
+        }
+        Ok(())
+    }
+
+    /// Merge the values `env` finds for each field's env var
+    fn merge_env<E: Fn(&str) -> Option<String>>(&mut self, env: E) {
+        for (i, field) in FIELDS.iter().enumerate() {
+            if let Some(v) = env(field.env) {
+                self.values[i] = (v, Source::Env(field.env));
+            }
+        }
+    }
+
+    fn get(&self, name: &str) -> &(String, Source) {
+        let i = Self::index(name).expect("config field not defined");
+        &self.values[i]
+    }
+
+    fn invalid(&self, name: &str, expected: &str) -> Error {
+        let (value, source) = self.get(name);
+        format_err!(
+            ErrorKind::InvalidConfig,
+            "invalid `{}` {:?} (from {}), expected {}",
+            name,
+            value,
+            source,
+            expected
+        )
+        .into()
+    }
+
+    fn string(&self, name: &str) -> Result<String> {
+        let value = &self.get(name).0;
+        if value.trim().is_empty() {
+            return Err(self.invalid(name, "a non-empty value"));
+        }
+        Ok(value.clone())
+    }
+
+    /// Trimmed value, `None` if it's empty
+    fn optional(&self, name: &str) -> Option<String> {
+        let value = self.get(name).0.trim();
+        if value.is_empty() {
+            None
+        } else {
+            Some(value.to_string())
+        }
+    }
+
+    fn parse<T: FromStr>(&self, name: &str, expected: &str) -> Result<T> {
+        self.get(name)
+            .0
+            .trim()
+            .parse()
+            .map_err(|_| self.invalid(name, expected))
+    }
+
+    fn positive<T: FromStr + PartialOrd + Default>(&self, name: &str) -> Result<T> {
+        let n: T = self.parse(name, "a positive integer")?;
+        if n <= T::default() {
+            return Err(self.invalid(name, "a positive integer"));
+        }
+        Ok(n)
+    }
+
+    /// Case-insensitive match against `allowed`, returning the matching `allowed` value
+    fn one_of(&self, name: &str, allowed: &[&str]) -> Result<String> {
+        let value = &self.get(name).0;
+        allowed
+            .iter()
+            .find(|a| a.eq_ignore_ascii_case(value.trim()))
+            .map(|a| a.to_string())
+            .ok_or_else(|| self.invalid(name, &format!("one of {:?}", allowed)))
+    }
+
+    /// Read a key from the file named by `file_field` if it's set,
+    /// otherwise from `name`
+    fn key(&self, name: &str, file_field: &str) -> Result<String> {
+        let key = self.key_value(name, file_field)?;
+        if key.is_empty() {
+            if self.get(file_field).0.trim().is_empty() {
+                return Err(self.invalid(name, "a non-empty value"));
+            }
+            return Err(self.invalid(file_field, "a file containing a key"));
+        }
+        Ok(key)
+    }
+
+    /// Same as `key`, but allows empty values
+    fn key_value(&self, name: &str, file_field: &str) -> Result<String> {
+        let (path, source) = self.get(file_field);
+        let path = path.trim();
+        if path.is_empty() {
+            return Ok(self.get(name).0.trim().to_string());
+        }
+        if !matches!(self.get(name).1, Source::Default) {
+            bail_fmt!(
+                ErrorKind::InvalidConfig,
+                "both `{}` (from {}) and `{}` (from {}) are set, expected one",
+                name,
+                self.get(name).1,
+                file_field,
+                source
+            );
+        }
+        let key = fs::read_to_string(path).map_err(|e| {
+            format_err!(
+                ErrorKind::InvalidConfig,
+                "invalid `{}` {:?} (from {}), can't read key: {}",
+                file_field,
+                path,
+                source,
+                e
+            )
+        })?;
+        Ok(key.trim().to_string())
+    }
+
+    /// Validate a key id, which is stored with the data it protects
+    fn key_id(&self, name: &str) -> Result<String> {
+        let id = self.string(name)?;
+        if id.contains(|c: char| c == ':' || c == ',' || c.is_whitespace()) {
+            return Err(self.invalid(name, "no `:`, `,` or whitespace"));
+        }
+        Ok(id)
+    }
+
+    /// Parse retired `id:key` pairs, which must not reuse the current key's id
+    fn retired_keys(
+        &self,
+        name: &str,
+        file_field: &str,
+        current_id: &str,
+    ) -> Result<BTreeMap<String, String>> {
+        let value = self.key_value(name, file_field)?;
+        let mut keys = BTreeMap::new();
+        for pair in value.split(|c: char| c == ',' || c.is_whitespace()) {
+            if pair.is_empty() {
+                continue;
+            }
+            let (id, key) = match pair.split_once(':') {
+                Some((id, key)) if !id.is_empty() && !key.is_empty() => (id, key),
+                _ => bail_fmt!(
+                    ErrorKind::InvalidConfig,
+                    "invalid `{}`, expected `id:key` pairs",
+                    name
+                ),
+            };
+            if id == current_id || keys.insert(id.to_string(), key.to_string()).is_some() {
+                bail_fmt!(
+                    ErrorKind::InvalidConfig,
+                    "invalid `{}`, duplicate key id `{}`",
+                    name,
+                    id
+                );
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Validate all values, naming the offending field on failure
+    pub fn build(&self) -> Result<Config> {
+        let version = std::fs::File::open("commit_hash.txt")
+            .map(|mut f| {
+                let mut s = String::new();
+                f.read_to_string(&mut s).expect("Error reading commit_hash");
+                s.trim().to_string()
+            })
+            .unwrap_or_else(|_| "unknown".to_string());
+        let encryption_key_id = self.key_id("encryption_key_id")?;
+        let signing_key_id = self.key_id("signing_key_id")?;
+        Ok(Config {
+            version,
+            mode: self.one_of("mode", &["dev", "prod"])?,
+            host: self.string("host")?,
+            port: self.positive("port")?,
+            log_level: self.string("log_level")?,
+            log_format: self.one_of("log_format", &["text", "json"])?,
+            encryption_key: self.key("encryption_key", "encryption_key_file")?,
+            retired_encryption_keys: self.retired_keys(
+                "retired_encryption_keys",
+                "retired_encryption_keys_file",
+                &encryption_key_id,
+            )?,
+            encryption_key_id,
+            signing_key: self.key("signing_key", "signing_key_file")?,
+            retired_signing_keys: self.retired_keys(
+                "retired_signing_keys",
+                "retired_signing_keys_file",
+                &signing_key_id,
+            )?,
+            signing_key_id,
+            max_paste_bytes: self.positive("max_paste_bytes")?,
+            max_paste_age_seconds: self.positive("max_paste_age_seconds")?,
+            max_ttl_seconds: self.positive("max_ttl_seconds")?,
+            paste_cache_size: self.parse("paste_cache_size", "a non-negative integer")?,
+            paste_cache_ttl_seconds: self.positive("paste_cache_ttl_seconds")?,
+            view_flush_interval_seconds: self.positive("view_flush_interval_seconds")?,
+            db_journal_mode: self.one_of(
+                "db_journal_mode",
+                &["DELETE", "TRUNCATE", "PERSIST", "MEMORY", "WAL", "OFF"],
+            )?,
+            db_synchronous: self.one_of("db_synchronous", &["OFF", "NORMAL", "FULL", "EXTRA"])?,
+            db_busy_timeout_ms: self.parse("db_busy_timeout_ms", "a non-negative integer")?,
+            db_pool_size: self.positive("db_pool_size")?,
+            db_connection_timeout_seconds: self.positive("db_connection_timeout_seconds")?,
+            min_free_disk_bytes: self.parse("min_free_disk_bytes", "a non-negative integer")?,
+            shutdown_timeout_seconds: self
+                .parse("shutdown_timeout_seconds", "a non-negative integer")?,
+            client_ip_header: self.optional("client_ip_header"),
+            decrypt_free_attempts: self.parse("decrypt_free_attempts", "a non-negative integer")?,
+            decrypt_backoff_max_seconds: self.positive("decrypt_backoff_max_seconds")?,
+            key_miss_free_attempts: self
+                .parse("key_miss_free_attempts", "a non-negative integer")?,
+            key_miss_backoff_max_seconds: self.positive("key_miss_backoff_max_seconds")?,
+            kdf_workers: self.positive("kdf_workers")?,
+            kdf_queue_size: self.parse("kdf_queue_size", "a non-negative integer")?,
+        })
+    }
+}
+
+/// Effective config values and their sources, with secrets redacted
+impl fmt::Display for RawConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (field, (value, source)) in FIELDS.iter().zip(self.values.iter()) {
+            let value = if field.secret { "<redacted>" } else { value };
+            writeln!(f, "{} = {:?}  # {}", field.name, value, source)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub version: String,
+
+    // `prod` refuses to start with weak keys, `dev` only warns
+    pub mode: String,
+
+    // host to listen on, defaults to localhost
+    pub host: String,
+    pub port: u16,
+
+    pub log_level: String,
+    // `text` or `json`
+    pub log_format: String,
+
+    // master key that all pastes are encrypted with at rest
+    pub encryption_key: String,
+    // stored alongside each paste to find the key that decrypts it
+    pub encryption_key_id: String,
+    // old master keys by id, only used to decrypt pastes not yet re-encrypted
+    pub retired_encryption_keys: BTreeMap<String, String>,
+    // key used to derive signature of paste content
+    pub signing_key: String,
+    // stored alongside each signature to find the key that verifies it
+    pub signing_key_id: String,
+    // old signing keys by id, only used to verify existing signatures
+    pub retired_signing_keys: BTreeMap<String, String>,
+
+    pub max_paste_bytes: usize,
+    pub max_paste_age_seconds: i64,
+    // longest explicit expiry, pastes with one aren't subject to `max_paste_age_seconds`
+    pub max_ttl_seconds: u32,
+
+    // max number of unencrypted pastes kept in memory, 0 disables the cache
+    pub paste_cache_size: usize,
+
+    // how long a cached paste is served before it's read again, so
+    // changes made by `admin` commands are seen by a running server
+    pub paste_cache_ttl_seconds: u64,
+
+    // how often recorded paste views are written to the database
+    pub view_flush_interval_seconds: u64,
+
+    // sqlite `journal_mode` and `synchronous` pragmas applied to pooled connections
+    pub db_journal_mode: String,
+    pub db_synchronous: String,
+    // how long a connection waits on a locked database before giving up
+    pub db_busy_timeout_ms: u64,
+    pub db_pool_size: u32,
+    // how long a request waits for a free pooled connection
+    pub db_connection_timeout_seconds: u64,
+
+    // `/status/ready` fails when the database's disk has less free space than this
+    pub min_free_disk_bytes: u64,
+
+    // how long to wait on in-flight requests when shutting down
+    pub shutdown_timeout_seconds: u64,
+
+    // header holding the client's address when running behind a proxy,
+    // e.g. `Fly-Client-IP`, otherwise the peer address is used
+    pub client_ip_header: Option<String>,
+
+    // failed decryptions allowed per paste and per client before backing off,
+    // after which each failure doubles the wait, up to `decrypt_backoff_max_seconds`
+    pub decrypt_free_attempts: u32,
+    pub decrypt_backoff_max_seconds: u64,
+
+    // lookups of pastes that don't exist allowed per client before backing off,
+    // like `decrypt_free_attempts`, to slow down key enumeration
+    pub key_miss_free_attempts: u32,
+    pub key_miss_backoff_max_seconds: u64,
+
+    // threads stretching user encryption keys, and how many requests
+    // may wait on them before new ones are refused with a 503
+    pub kdf_workers: usize,
+    pub kdf_queue_size: usize,
+}
+impl Config {
+    /// Collect defaults, the config file at `path` and env vars,
+    /// without validating them
+    pub fn load_raw(path: Option<&Path>) -> Result<RawConfig> {
+        Self::load_raw_with_env(path, |name| std::env::var(name).ok())
+    }
+
+    /// `load_raw`, looking up env vars with `env` instead of
+    /// reading the process environment
+    pub fn load_raw_with_env<E>(path: Option<&Path>, env: E) -> Result<RawConfig>
+    where
+        E: Fn(&str) -> Option<String>,
+    {
+        let mut raw = RawConfig::defaults();
+        if let Some(path) = path {
+            raw.merge_file(path)?;
+        }
+        raw.merge_env(env);
+        Ok(raw)
+    }
+
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        Self::load_raw(path)?.build()
+    }
+
+    /// `load`, looking up env vars with `env`, see `load_raw_with_env`
+    pub fn load_with_env<E>(path: Option<&Path>, env: E) -> Result<Self>
+    where
+        E: Fn(&str) -> Option<String>,
+    {
+        Self::load_raw_with_env(path, env)?.build()
+    }
+
+    pub fn host(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// Find a master encryption key by id, current or retired
+    pub fn encryption_key(&self, id: &str) -> Option<&str> {
+        if id == self.encryption_key_id {
+            return Some(&self.encryption_key);
+        }
+        self.retired_encryption_keys.get(id).map(String::as_str)
+    }
+
+    /// Find a signing key by id, current or retired
+    pub fn signing_key(&self, id: &str) -> Option<&str> {
+        if id == self.signing_key_id {
+            return Some(&self.signing_key);
+        }
+        self.retired_signing_keys.get(id).map(String::as_str)
+    }
+
+    /// All signing keys, starting with the current key
+    pub fn signing_keys(&self) -> impl Iterator<Item = (&str, &str)> {
+        std::iter::once((self.signing_key_id.as_str(), self.signing_key.as_str())).chain(
+            self.retired_signing_keys
+                .iter()
+                .map(|(id, key)| (id.as_str(), key.as_str())),
+        )
+    }
+
+    pub fn is_production(&self) -> bool {
+        self.mode == "prod"
+    }
+
+    /// Describe any default or short keys, retired ones included
+    /// since they can still decrypt and verify old pastes
+    pub fn weak_keys(&self) -> Vec<String> {
+        let current = [
+            ("encryption_key".to_string(), &self.encryption_key),
+            ("signing_key".to_string(), &self.signing_key),
+        ];
+        let retired = [
+            ("retired_encryption_keys", &self.retired_encryption_keys),
+            ("retired_signing_keys", &self.retired_signing_keys),
+        ];
+        let retired = retired.iter().flat_map(|(name, keys)| {
+            keys.iter()
+                .map(move |(id, key)| (format!("{}.{}", name, id), key))
+        });
+        current
+            .into_iter()
+            .chain(retired)
+            .filter_map(|(name, key)| {
+                if key.as_str() == DEFAULT_KEY {
+                    Some(format!("`{}` is the public default key", name))
+                } else if key.len() < MIN_KEY_BYTES {
+                    Some(format!(
+                        "`{}` is shorter than {} bytes",
+                        name, MIN_KEY_BYTES
+                    ))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Fail on weak keys in `prod` mode, returning them as warnings in `dev` mode
+    pub fn check_keys(&self) -> Result<Vec<String>> {
+        let weak = self.weak_keys();
+        if !weak.is_empty() && self.is_production() {
+            bail_fmt!(
+                ErrorKind::InvalidConfig,
+                "{}. Generate keys with `upaste admin keygen`, or set `mode = \"dev\"`",
+                weak.join(", ")
+            );
+        }
+        Ok(weak)
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// Defaults overridden by `vars`, never the process environment
+    pub(crate) fn with_env(vars: &[(&str, &str)]) -> Self {
+        let vars = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<BTreeMap<_, _>>();
+        Self::load_with_env(None, |name| vars.get(name).cloned()).unwrap()
+    }
+}
+
+/// Handle `upaste config` subcommands
+pub fn handle(matches: &ArgMatches, path: Option<&Path>) -> Result<()> {
+    match matches.subcommand() {
+        ("check", _) => {
+            let config = Config::load(path)?;
+            for warning in config.check_keys()? {
+                println!("Warning: {}", warning);
+            }
+            println!("Config ok");
+        }
+        ("show", _) => {
+            let raw = Config::load_raw(path)?;
+            raw.build()?;
+            print!("{}", raw);
+        }
+        _ => println!("See: upaste config --help"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_overrides_defaults() {
+        let config = Config::with_env(&[]);
+        assert_eq!(config.mode, "prod");
+        assert_eq!(config.port, 3003);
+
+        let config = Config::with_env(&[("MODE", "dev"), ("PORT", "4000")]);
+        assert_eq!(config.mode, "dev");
+        assert_eq!(config.port, 4000);
+        let raw = Config::load_raw_with_env(None, |name| {
+            (name == "PORT").then(|| "not a port".to_string())
+        })
+        .unwrap();
+        assert!(raw.build().is_err());
+    }
+
+    #[test]
+    fn weak_retired_keys() {
+        let strong = "0123456789abcdef".repeat(4);
+        let config = Config::with_env(&[("ENCRYPTION_KEY", &strong), ("SIGNING_KEY", &strong)]);
+        assert!(config.weak_keys().is_empty());
+
+        let config = Config::with_env(&[
+            ("ENCRYPTION_KEY", &strong),
+            ("SIGNING_KEY", &strong),
+            ("RETIRED_SIGNING_KEYS", &format!("old:{}", DEFAULT_KEY)),
+            (
+                "RETIRED_ENCRYPTION_KEYS",
+                &format!("older:short, ok:{}", strong),
+            ),
+        ]);
+        assert_eq!(
+            config.weak_keys(),
+            [
+                format!(
+                    "`retired_encryption_keys.older` is shorter than {} bytes",
+                    MIN_KEY_BYTES
+                ),
+                "`retired_signing_keys.old` is the public default key".to_string(),
+            ]
+        );
+    }
+}
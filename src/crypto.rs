@@ -2,7 +2,8 @@
 Crypto things
 */
 use ring::aead::BoundKey;
-use ring::pbkdf2;
+use ring::{hkdf, pbkdf2};
+use serde::{Deserialize, Serialize};
 
 use std::num::NonZeroU32;
 
@@ -44,113 +45,701 @@ pub fn new_salt() -> crate::Result<Vec<u8>> {
     rand_bytes(16)
 }
 
-pub fn hmac_sign_with_key(s: &str, key: &str) -> String {
+/// Hex encoded SHA256 digest of `s`
+pub fn sha256_hex(s: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, s.as_bytes());
+    hex::encode(digest)
+}
+
+pub fn hmac_sign_with_key(s: &str, key: &str) -> Vec<u8> {
     // using a 32 byte key
     let s_key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key.as_bytes());
     let tag = ring::hmac::sign(&s_key, s.as_bytes());
-    hex::encode(&tag)
+    tag.as_ref().to_vec()
 }
 
-pub fn hmac_verify_with_key(text: &str, sig: &str, key: &str) -> bool {
-    let sig = hex::decode(sig);
-    let sig = if let Ok(sig) = sig {
-        sig
-    } else {
-        return false;
-    };
+pub fn hmac_verify_with_key(text: &str, sig: &[u8], key: &str) -> bool {
     // using a 32 byte key
     let s_key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key.as_bytes());
-    ring::hmac::verify(&s_key, text.as_bytes(), &sig).is_ok()
+    ring::hmac::verify(&s_key, text.as_bytes(), sig).is_ok()
 }
 
-/// The resulting stretched key must be the same length as the
-/// encryption algorithm's key-length. For us, the alg is
-/// AES_256_GCM whose key-length is 32-bytes
-pub fn derive_encryption_key(pw: &[u8], salt: &[u8]) -> [u8; 32] {
-    let mut out = [0; 32];
-    pbkdf2::derive(
-        pbkdf2::PBKDF2_HMAC_SHA512,
-        NonZeroU32::new(100_000u32).unwrap(),
-        salt,
-        pw,
-        &mut out,
-    );
-    out
+/// HMAC key derived from a signing key for a single `purpose`. Content
+/// signatures use the signing key itself, so tags over attacker chosen
+/// content can never double as share links.
+fn purpose_key(signing_key: &str, purpose: &[u8]) -> crate::Result<ring::hmac::Key> {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, b"upaste hmac").extract(signing_key.as_bytes());
+    let info = [purpose];
+    let okm = prk
+        .expand(&info, ring::hmac::HMAC_SHA256)
+        .map_err(|_| "Error deriving hmac key")?;
+    Ok(okm.into())
 }
 
-/// Encrypt `bytes` with the given `nonce` and `pass`
-///
-/// `bytes` are encrypted using AES_256_GCM, `nonce` is expected to be
-/// 12-bytes, and `pass` 32-bytes
-pub fn encrypt_bytes(
-    bytes: &[u8],
-    nonce: &[u8],
-    pass: &[u8],
-    salt: &[u8],
+/// Share links name pastes by id, the key is signed as well so a link
+/// can't open a later paste that reuses the id of a deleted one
+fn share_link_message(paste_id: i64, paste_key: &str, expires: i64, generation: u32) -> String {
+    format!(
+        "upaste-share-v2\n{}\n{}\n{}\n{}",
+        paste_id, paste_key, expires, generation
+    )
+}
+
+/// HMAC authorizing links to a paste until `expires`, links minted
+/// under an older `generation` are revoked
+pub fn share_link_signature(
+    paste_id: i64,
+    paste_key: &str,
+    expires: i64,
+    generation: u32,
+    signing_key: &str,
 ) -> crate::Result<Vec<u8>> {
+    let key = purpose_key(signing_key, b"upaste share link")?;
+    let message = share_link_message(paste_id, paste_key, expires, generation);
+    Ok(ring::hmac::sign(&key, message.as_bytes()).as_ref().to_vec())
+}
+
+/// Check a share link signature against the paste's current
+/// `generation`, links are only valid before `expires`
+pub fn share_link_verify(
+    paste_id: i64,
+    paste_key: &str,
+    expires: i64,
+    generation: u32,
+    sig: &[u8],
+    signing_key: &str,
+    now: i64,
+) -> bool {
+    if expires <= now {
+        return false;
+    }
+    let message = share_link_message(paste_id, paste_key, expires, generation);
+    purpose_key(signing_key, b"upaste share link")
+        .map(|key| ring::hmac::verify(&key, message.as_bytes(), sig).is_ok())
+        .unwrap_or(false)
+}
+
+/// The statement a paste's Ed25519 signature covers, anyone with the
+/// public key can rebuild it from the paste to check the signature
+pub fn attestation(paste_key: &str, content_sha256: &str, date_created: i64) -> String {
+    format!(
+        "upaste-v1\n{}\n{}\n{}",
+        paste_key, content_sha256, date_created
+    )
+}
+
+/// Ed25519 key pair derived from a signing key, so it rotates along
+/// with `signing_key_id` and needs no extra secret
+fn ed25519_keypair(signing_key: &str) -> crate::Result<ring::signature::Ed25519KeyPair> {
+    let mut seed = [0; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, b"upaste ed25519")
+        .extract(signing_key.as_bytes())
+        .expand(&[b"upaste ed25519 seed"], hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut seed))
+        .map_err(|_| "Error deriving ed25519 seed")?;
+    let pair = ring::signature::Ed25519KeyPair::from_seed_unchecked(&seed)
+        .map_err(|_| "Error creating ed25519 key pair")?;
+    Ok(pair)
+}
+
+pub fn ed25519_public_key(signing_key: &str) -> crate::Result<Vec<u8>> {
+    use ring::signature::KeyPair;
+    Ok(ed25519_keypair(signing_key)?.public_key().as_ref().to_vec())
+}
+
+pub fn ed25519_sign(message: &str, signing_key: &str) -> crate::Result<Vec<u8>> {
+    Ok(ed25519_keypair(signing_key)?
+        .sign(message.as_bytes())
+        .as_ref()
+        .to_vec())
+}
+
+pub fn ed25519_verify(message: &str, sig: &[u8], public_key: &[u8]) -> bool {
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+        .verify(message.as_bytes(), sig)
+        .is_ok()
+}
+
+/// Key derivation function, and its parameters, used to stretch a key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "name")]
+pub enum Kdf {
+    #[serde(rename = "pbkdf2-sha512")]
+    Pbkdf2Sha512 { iterations: u32 },
+    #[serde(rename = "argon2id")]
+    Argon2id {
+        // KiB
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+    // only for high entropy keys, see `derive_data_key`
+    #[serde(rename = "hkdf-sha256")]
+    HkdfSha256,
+}
+
+/// Describes how a ciphertext was produced. Stored alongside it so
+/// parameters can be strengthened without breaking existing rows.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    // 0: no associated data
+    // 1: the paste key is bound as associated data
+    pub v: u32,
+    pub alg: String,
+    pub kdf: Kdf,
+}
+
+static AES_256_GCM: &str = "aes-256-gcm";
+
+impl Envelope {
+    /// User key encryption of rows written before envelopes were recorded
+    pub fn legacy() -> Self {
+        Self {
+            v: 0,
+            alg: AES_256_GCM.into(),
+            kdf: Kdf::Pbkdf2Sha512 {
+                iterations: 100_000,
+            },
+        }
+    }
+
+    /// At-rest encryption of rows written before envelopes were recorded
+    pub fn legacy_at_rest() -> Self {
+        Self {
+            v: 0,
+            alg: AES_256_GCM.into(),
+            kdf: Kdf::HkdfSha256,
+        }
+    }
+
+    /// Used for all new user key encryption
+    pub fn current() -> Self {
+        Self {
+            v: 1,
+            alg: AES_256_GCM.into(),
+            kdf: Kdf::Argon2id {
+                m_cost: 19 * 1024,
+                t_cost: 2,
+                p_cost: 1,
+            },
+        }
+    }
+
+    /// Used for all new at-rest encryption
+    pub fn current_at_rest() -> Self {
+        Self {
+            v: 1,
+            ..Self::legacy_at_rest()
+        }
+    }
+
+    /// The associated data to use for this version
+    fn aad<'a>(&self, aad: &'a [u8]) -> crate::Result<&'a [u8]> {
+        if self.alg != AES_256_GCM {
+            bail!(format!("unsupported encryption algorithm {:?}", self.alg));
+        }
+        match self.v {
+            0 => Ok(&[]),
+            1 => Ok(aad),
+            v => bail!(format!("unsupported envelope version {}", v)),
+        }
+    }
+
+    /// Stretch `pass` into a 32-byte AES_256_GCM key
+    fn derive_key(&self, pass: &[u8], salt: &[u8]) -> crate::Result<[u8; 32]> {
+        let mut out = [0; 32];
+        match self.kdf {
+            Kdf::Pbkdf2Sha512 { iterations } => {
+                let iterations = NonZeroU32::new(iterations).ok_or("invalid pbkdf2 iterations")?;
+                pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA512, iterations, salt, pass, &mut out);
+            }
+            Kdf::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+            } => {
+                let params = argon2::Params::new(m_cost, t_cost, p_cost, Some(out.len()))
+                    .map_err(|e| format!("invalid argon2 params: {}", e))?;
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(pass, salt, &mut out)
+                    .map_err(|e| format!("Error deriving key: {}", e))?;
+            }
+            Kdf::HkdfSha256 => out = derive_data_key(pass, salt)?,
+        }
+        Ok(out)
+    }
+}
+
+/// Derive a paste's at-rest data key from a high entropy master key.
+/// Unlike password based kdfs this is cheap enough to run on every read.
+fn derive_data_key(master_key: &[u8], salt: &[u8]) -> crate::Result<[u8; 32]> {
+    let mut out = [0; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, salt)
+        .extract(master_key)
+        .expand(&[b"upaste at-rest data key"], hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut out))
+        .map_err(|_| "Error deriving data key")?;
+    Ok(out)
+}
+
+/// Encrypt `bytes` with the given `nonce` and already stretched 32-byte `key`
+fn seal(bytes: &[u8], nonce: &[u8], key: &[u8], aad: &[u8]) -> crate::Result<Vec<u8>> {
     let alg = &ring::aead::AES_256_GCM;
     let nonce = ring::aead::Nonce::try_assume_unique_for_key(nonce)
         .map_err(|_| "Encryption nonce not unique")?;
     let nonce = OneNonceSequence::new(nonce);
-
-    let stretched = derive_encryption_key(pass, salt);
-    let key =
-        ring::aead::UnboundKey::new(alg, &stretched).map_err(|_| "Error building sealing key")?;
+    let key = ring::aead::UnboundKey::new(alg, key).map_err(|_| "Error building sealing key")?;
     let mut key = ring::aead::SealingKey::new(key, nonce);
     let mut in_out = bytes.to_vec();
-    key.seal_in_place_append_tag(ring::aead::Aad::empty(), &mut in_out)
+    key.seal_in_place_append_tag(ring::aead::Aad::from(aad), &mut in_out)
         .map_err(|_| "Failed encrypting bytes")?;
     Ok(in_out)
 }
 
-/// Decrypt `bytes` with the given `nonce` and `pass`
-///
-/// `bytes` are decrypted using AES_256_GCM, `nonce` is expected to be
-/// 12-bytes, and `pass` 32-bytes
-pub fn decrypt_bytes<'a>(
-    bytes: &'a mut [u8],
-    nonce: &[u8],
-    pass: &[u8],
-    salt: &[u8],
-) -> crate::Result<&'a [u8]> {
+/// Decrypt `bytes` with the given `nonce` and already stretched 32-byte `key`
+fn open<'a>(bytes: &'a mut [u8], nonce: &[u8], key: &[u8], aad: &[u8]) -> crate::Result<&'a [u8]> {
     let alg = &ring::aead::AES_256_GCM;
     let nonce = ring::aead::Nonce::try_assume_unique_for_key(nonce)
         .map_err(|_| "Decryption nonce not unique")?;
     let nonce = OneNonceSequence::new(nonce);
-    let stretched = derive_encryption_key(pass, salt);
-    let key =
-        ring::aead::UnboundKey::new(alg, &stretched).map_err(|_| "Error build opening key")?;
+    let key = ring::aead::UnboundKey::new(alg, key).map_err(|_| "Error build opening key")?;
     let mut key = ring::aead::OpeningKey::new(key, nonce);
     let out_slice = key
-        .open_in_place(ring::aead::Aad::empty(), bytes)
+        .open_in_place(ring::aead::Aad::from(aad), bytes)
         .map_err(|_| "Failed decrypting bytes")?;
     Ok(out_slice)
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Clone)]
+/// Encrypt `bytes` with the given `nonce` and `pass`
+///
+/// `bytes` are encrypted as described by `envelope`, `nonce` is expected
+/// to be 12-bytes. `aad` is authenticated but not encrypted, and is
+/// ignored by legacy envelopes.
+pub fn encrypt_bytes(
+    bytes: &[u8],
+    nonce: &[u8],
+    pass: &[u8],
+    salt: &[u8],
+    envelope: &Envelope,
+    aad: &[u8],
+) -> crate::Result<Vec<u8>> {
+    let aad = envelope.aad(aad)?;
+    let stretched = envelope.derive_key(pass, salt)?;
+    seal(bytes, nonce, &stretched, aad)
+}
+
+/// Decrypt `bytes` with the given `nonce` and `pass`, see `encrypt_bytes`
+pub fn decrypt_bytes<'a>(
+    bytes: &'a mut [u8],
+    nonce: &[u8],
+    pass: &[u8],
+    salt: &[u8],
+    envelope: &Envelope,
+    aad: &[u8],
+) -> crate::Result<&'a [u8]> {
+    let aad = envelope.aad(aad)?;
+    let stretched = envelope.derive_key(pass, salt)?;
+    open(bytes, nonce, &stretched, aad)
+}
+
+#[derive(Debug, Clone)]
 pub struct Enc {
-    pub value: String,
-    pub nonce: String,
-    pub salt: String,
+    pub value: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub salt: Vec<u8>,
+    pub envelope: Envelope,
 }
 
-pub fn encrypt_with_key(s: &str, key: &str) -> crate::Result<Enc> {
+fn encrypt(bytes: &[u8], key: &[u8], envelope: Envelope, aad: &[u8]) -> crate::Result<Enc> {
     let nonce = new_nonce().map_err(|_| "error generating nonce")?;
     let salt = new_salt().map_err(|_| "error generating salt")?;
-    let b = encrypt_bytes(s.as_bytes(), &nonce, key.as_bytes(), &salt)
-        .map_err(|_| "encryption error")?;
-    let value = hex::encode(&b);
-    let nonce = hex::encode(&nonce);
-    let salt = hex::encode(&salt);
-    Ok(Enc { value, nonce, salt })
-}
-
-pub fn decrypt_with_key(enc: &Enc, key: &str) -> crate::Result<String> {
-    let nonce = hex::decode(&enc.nonce).map_err(|_| "nonce hex decode error")?;
-    let salt = hex::decode(&enc.salt).map_err(|_| "salt hex decode error")?;
-    let mut value = hex::decode(&enc.value).map_err(|_| "value hex decode error")?;
-    let bytes = decrypt_bytes(value.as_mut_slice(), &nonce, key.as_bytes(), &salt)
-        .map_err(|_| "encryption error")?;
-    let s = String::from_utf8(bytes.to_owned()).map_err(|_| "error decrypting bytes")?;
+    let value =
+        encrypt_bytes(bytes, &nonce, key, &salt, &envelope, aad).map_err(|_| "encryption error")?;
+    Ok(Enc {
+        value,
+        nonce,
+        salt,
+        envelope,
+    })
+}
+
+fn decrypt(enc: &Enc, key: &[u8], aad: &[u8]) -> crate::Result<Vec<u8>> {
+    let mut value = enc.value.clone();
+    let bytes = decrypt_bytes(
+        value.as_mut_slice(),
+        &enc.nonce,
+        key,
+        &enc.salt,
+        &enc.envelope,
+        aad,
+    )
+    .map_err(|_| "encryption error")?;
+    Ok(bytes.to_owned())
+}
+
+/// Encrypt `s` with a user provided `key`, binding `aad` to the ciphertext
+pub fn encrypt_with_key(s: &str, key: &str, aad: &[u8]) -> crate::Result<Enc> {
+    encrypt(s.as_bytes(), key.as_bytes(), Envelope::current(), aad)
+}
+
+pub fn decrypt_with_key(enc: &Enc, key: &str, aad: &[u8]) -> crate::Result<String> {
+    let bytes = decrypt(enc, key.as_bytes(), aad)?;
+    let s = String::from_utf8(bytes).map_err(|_| "error decrypting bytes")?;
+    Ok(s)
+}
+
+/// Encrypt `bytes` at rest with a data key derived from `master_key`,
+/// binding `aad` to the ciphertext
+pub fn encrypt_at_rest(bytes: &[u8], master_key: &str, aad: &[u8]) -> crate::Result<Enc> {
+    encrypt(
+        bytes,
+        master_key.as_bytes(),
+        Envelope::current_at_rest(),
+        aad,
+    )
+}
+
+pub fn decrypt_at_rest(enc: &Enc, master_key: &str, aad: &[u8]) -> crate::Result<Vec<u8>> {
+    if enc.envelope.kdf != Kdf::HkdfSha256 {
+        bail!("at-rest encryption requires an hkdf envelope");
+    }
+    decrypt(enc, master_key.as_bytes(), aad)
+}
+
+/// A paste's data key, encrypted to one recipient's X25519 public key
+#[derive(Debug, Clone)]
+pub struct WrappedKey {
+    pub public_key: Vec<u8>,
+    // fresh for every wrapping, combined with the recipient's
+    // secret to recover the token that unwraps `value`
+    pub ephemeral_public_key: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+fn x25519_key(bytes: &[u8], what: &str) -> crate::Result<[u8; 32]> {
+    let mut key = [0; 32];
+    if bytes.len() != key.len() {
+        bail!(format!("{} must be 32 bytes", what));
+    }
+    key.copy_from_slice(bytes);
+    Ok(key)
+}
+
+/// Generate an X25519 (secret, public) key pair
+pub fn x25519_keypair() -> crate::Result<(Vec<u8>, Vec<u8>)> {
+    let secret = x25519_key(&rand_bytes(32)?, "secret key")?;
+    let public = x25519_public_key(&secret)?;
+    Ok((secret.to_vec(), public))
+}
+
+pub fn x25519_public_key(secret: &[u8]) -> crate::Result<Vec<u8>> {
+    let secret = x25519_dalek::StaticSecret::from(x25519_key(secret, "secret key")?);
+    Ok(x25519_dalek::PublicKey::from(&secret).as_bytes().to_vec())
+}
+
+/// The key wrapping key shared by the two sides of an X25519 exchange
+fn wrapping_token(
+    shared: &x25519_dalek::SharedSecret,
+    ephemeral_public_key: &[u8],
+    public_key: &[u8],
+) -> crate::Result<[u8; 32]> {
+    if !shared.was_contributory() {
+        bail!("non-contributory x25519 exchange");
+    }
+    let mut out = [0; 32];
+    hkdf::Salt::new(
+        hkdf::HKDF_SHA256,
+        &[ephemeral_public_key, public_key].concat(),
+    )
+    .extract(shared.as_bytes())
+    .expand(&[b"upaste recipient key wrap"], hkdf::HKDF_SHA256)
+    .and_then(|okm| okm.fill(&mut out))
+    .map_err(|_| "Error deriving key wrapping token")?;
+    Ok(out)
+}
+
+/// Encrypt `s` under a random data key, wrapped for each of the `recipients`
+/// X25519 public keys, binding `aad` to the ciphertext and wrapped keys
+pub fn encrypt_for_recipients(
+    s: &str,
+    recipients: &[Vec<u8>],
+    aad: &[u8],
+) -> crate::Result<(Enc, Vec<WrappedKey>)> {
+    let data_key = rand_bytes(32)?;
+    // the data key is random, so it only needs hkdf rather than a password kdf
+    let enc = encrypt(s.as_bytes(), &data_key, Envelope::current_at_rest(), aad)?;
+    let wrapped = recipients
+        .iter()
+        .map(|public_key| {
+            let public = x25519_dalek::PublicKey::from(x25519_key(public_key, "public key")?);
+            // used for this one exchange only, then dropped
+            let ephemeral =
+                x25519_dalek::StaticSecret::from(x25519_key(&rand_bytes(32)?, "secret key")?);
+            let ephemeral_public_key = x25519_dalek::PublicKey::from(&ephemeral)
+                .as_bytes()
+                .to_vec();
+            let token = wrapping_token(
+                &ephemeral.diffie_hellman(&public),
+                &ephemeral_public_key,
+                public_key,
+            )?;
+            let nonce = new_nonce()?;
+            let value = seal(&data_key, &nonce, &token, aad)?;
+            Ok(WrappedKey {
+                public_key: public_key.clone(),
+                ephemeral_public_key,
+                nonce,
+                value,
+            })
+        })
+        .collect::<crate::Result<Vec<_>>>()?;
+    Ok((enc, wrapped))
+}
+
+/// The token that unwraps `wrapped`, only computable with the recipient's
+/// `secret`. Clients can derive it themselves to avoid sending their secret.
+pub fn recipient_token(wrapped: &WrappedKey, secret: &[u8]) -> crate::Result<[u8; 32]> {
+    let secret = x25519_dalek::StaticSecret::from(x25519_key(secret, "secret key")?);
+    let ephemeral =
+        x25519_dalek::PublicKey::from(x25519_key(&wrapped.ephemeral_public_key, "public key")?);
+    wrapping_token(
+        &secret.diffie_hellman(&ephemeral),
+        &wrapped.ephemeral_public_key,
+        &wrapped.public_key,
+    )
+}
+
+/// Decrypt a paste encrypted for recipients with a `token` unwrapping any of its `wrapped` keys
+pub fn decrypt_as_recipient(
+    enc: &Enc,
+    wrapped: &[WrappedKey],
+    token: &[u8],
+    aad: &[u8],
+) -> crate::Result<String> {
+    let data_key = wrapped
+        .iter()
+        .find_map(|w| {
+            let mut value = w.value.clone();
+            open(&mut value, &w.nonce, token, aad)
+                .ok()
+                .map(<[u8]>::to_vec)
+        })
+        .ok_or("no recipient key matches")?;
+    let bytes = decrypt(enc, &data_key, aad)?;
+    let s = String::from_utf8(bytes).map_err(|_| "error decrypting bytes")?;
     Ok(s)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_envelope_round_trip() {
+        let enc = encrypt(b"hello", b"pass", Envelope::legacy(), b"key").unwrap();
+        // v0 predates associated data, so none is bound
+        assert_eq!(decrypt_with_key(&enc, "pass", b"other").unwrap(), "hello");
+        assert!(decrypt_with_key(&enc, "wrong", b"key").is_err());
+    }
+
+    #[test]
+    fn current_envelope_binds_aad() {
+        let enc = encrypt_with_key("hello", "pass", b"key").unwrap();
+        assert_eq!(enc.envelope, Envelope::current());
+        assert_eq!(decrypt_with_key(&enc, "pass", b"key").unwrap(), "hello");
+        assert!(decrypt_with_key(&enc, "pass", b"other").is_err());
+        assert!(decrypt_with_key(&enc, "wrong", b"key").is_err());
+    }
+
+    #[test]
+    fn at_rest_requires_hkdf() {
+        let enc = encrypt_at_rest(b"hello", "master", b"key").unwrap();
+        assert_eq!(decrypt_at_rest(&enc, "master", b"key").unwrap(), b"hello");
+        assert!(decrypt_at_rest(&enc, "master", b"other").is_err());
+        let user = encrypt(b"hello", b"master", Envelope::legacy(), b"key").unwrap();
+        assert!(decrypt_at_rest(&user, "master", b"key").is_err());
+    }
+
+    #[test]
+    fn share_link_signatures() {
+        let signing_key = "0123456789abcdef0123456789abcdef";
+        let sig = share_link_signature(7, "abcde", 2000, 0, signing_key).unwrap();
+        assert!(share_link_verify(
+            7,
+            "abcde",
+            2000,
+            0,
+            &sig,
+            signing_key,
+            1000
+        ));
+        // bound to the paste, its key and expiry, and the signing key
+        assert!(!share_link_verify(
+            8,
+            "abcde",
+            2000,
+            0,
+            &sig,
+            signing_key,
+            1000
+        ));
+        assert!(!share_link_verify(
+            7,
+            "abcdf",
+            2000,
+            0,
+            &sig,
+            signing_key,
+            1000
+        ));
+        assert!(!share_link_verify(
+            7,
+            "abcde",
+            3000,
+            0,
+            &sig,
+            signing_key,
+            1000
+        ));
+        let other_key = "fedcba9876543210fedcba9876543210";
+        assert!(!share_link_verify(
+            7, "abcde", 2000, 0, &sig, other_key, 1000
+        ));
+        // and never doubles as a content signature
+        assert_ne!(sig, hmac_sign_with_key("abcde", signing_key));
+    }
+
+    #[test]
+    fn share_link_expiry() {
+        let signing_key = "0123456789abcdef0123456789abcdef";
+        let sig = share_link_signature(7, "abcde", 2000, 0, signing_key).unwrap();
+        assert!(share_link_verify(
+            7,
+            "abcde",
+            2000,
+            0,
+            &sig,
+            signing_key,
+            1999
+        ));
+        assert!(!share_link_verify(
+            7,
+            "abcde",
+            2000,
+            0,
+            &sig,
+            signing_key,
+            2000
+        ));
+        assert!(!share_link_verify(
+            7,
+            "abcde",
+            2000,
+            0,
+            &sig,
+            signing_key,
+            2001
+        ));
+    }
+
+    #[test]
+    fn share_link_revocation() {
+        let signing_key = "0123456789abcdef0123456789abcdef";
+        let sig = share_link_signature(7, "abcde", 2000, 0, signing_key).unwrap();
+        // bumping the paste's `share_generation` revokes older links
+        assert!(!share_link_verify(
+            7,
+            "abcde",
+            2000,
+            1,
+            &sig,
+            signing_key,
+            1000
+        ));
+        let sig = share_link_signature(7, "abcde", 2000, 1, signing_key).unwrap();
+        assert!(share_link_verify(
+            7,
+            "abcde",
+            2000,
+            1,
+            &sig,
+            signing_key,
+            1000
+        ));
+    }
+
+    #[test]
+    fn recipient_round_trip() {
+        let (alice_secret, alice_public) = x25519_keypair().unwrap();
+        let (bob_secret, bob_public) = x25519_keypair().unwrap();
+        let (enc, wrapped) =
+            encrypt_for_recipients("hello", &[alice_public, bob_public], b"key").unwrap();
+        for (secret, w) in [(&alice_secret, &wrapped[0]), (&bob_secret, &wrapped[1])] {
+            let token = recipient_token(w, secret).unwrap();
+            assert_eq!(
+                decrypt_as_recipient(&enc, &wrapped, &token, b"key").unwrap(),
+                "hello"
+            );
+            // bound to the paste
+            assert!(decrypt_as_recipient(&enc, &wrapped, &token, b"other").is_err());
+        }
+    }
+
+    #[test]
+    fn wrong_recipient_fails() {
+        let (_, alice_public) = x25519_keypair().unwrap();
+        let (eve_secret, _) = x25519_keypair().unwrap();
+        let (enc, wrapped) = encrypt_for_recipients("hello", &[alice_public], b"key").unwrap();
+        let token = recipient_token(&wrapped[0], &eve_secret).unwrap();
+        assert!(decrypt_as_recipient(&enc, &wrapped, &token, b"key").is_err());
+        assert!(decrypt_as_recipient(&enc, &wrapped, &[0; 32], b"key").is_err());
+    }
+
+    #[test]
+    fn low_order_points_rejected() {
+        // the identity and a point of order 8, their shared
+        // secrets are all zeros whatever the other side's key
+        let zero = vec![0; 32];
+        let mut order_eight = vec![0; 32];
+        order_eight[..16].copy_from_slice(&[
+            0xe0, 0xeb, 0x7a, 0x7c, 0x3b, 0x41, 0xb8, 0xae, 0x16, 0x56, 0xe3, 0xfa, 0xf1, 0x9f,
+            0xc4, 0x6a,
+        ]);
+        order_eight[16..].copy_from_slice(&[
+            0xda, 0x09, 0x8d, 0xeb, 0x9c, 0x32, 0xb1, 0xfd, 0x86, 0x62, 0x05, 0x16, 0x5f, 0x49,
+            0xb8, 0x00,
+        ]);
+        let (secret, public) = x25519_keypair().unwrap();
+        for point in [zero, order_eight] {
+            assert!(encrypt_for_recipients("hello", std::slice::from_ref(&point), b"key").is_err());
+            let (_, mut wrapped) =
+                encrypt_for_recipients("hello", std::slice::from_ref(&public), b"key").unwrap();
+            wrapped[0].ephemeral_public_key = point;
+            assert!(recipient_token(&wrapped[0], &secret).is_err());
+        }
+    }
+
+    #[test]
+    fn ed25519_signatures() {
+        let signing_key = "0123456789abcdef0123456789abcdef";
+        let message = attestation("abcde", &sha256_hex("hello"), 1_600_000_000);
+        let sig = ed25519_sign(&message, signing_key).unwrap();
+        // as published at `/.well-known/upaste-key`
+        let published = hex::encode(ed25519_public_key(signing_key).unwrap());
+        let public_key = hex::decode(published).unwrap();
+        assert!(ed25519_verify(&message, &sig, &public_key));
+
+        let other = ed25519_public_key("fedcba9876543210fedcba9876543210").unwrap();
+        assert!(!ed25519_verify(&message, &sig, &other));
+        let mut tampered = message.clone().into_bytes();
+        tampered[message.len() - 1] ^= 1;
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(!ed25519_verify(&tampered, &sig, &public_key));
+        for i in [0, sig.len() / 2, sig.len() - 1] {
+            let mut sig = sig.clone();
+            sig[i] ^= 1;
+            assert!(!ed25519_verify(&message, &sig, &public_key));
+        }
+    }
+}
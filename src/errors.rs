@@ -33,9 +33,25 @@ error_chain! {
             description("OutOfSpace")
             display("OutOfSpace Error: {}", s)
         }
+        Overloaded(s: String) {
+            description("Overloaded")
+            display("Overloaded Error: {}", s)
+        }
         DecryptionError(s: String) {
             description("DecryptionError")
             display("DecryptionError Error: {}", s)
         }
+        RecipientKeyRequired(ephemeral_public_key: Option<String>) {
+            description("RecipientKeyRequired")
+            display("RecipientKeyRequired Error: recipient key required")
+        }
+        TooManyAttempts(s: String, retry_after_seconds: u64) {
+            description("TooManyAttempts")
+            display("TooManyAttempts Error: {}, retry after {}s", s, retry_after_seconds)
+        }
+        InvalidConfig(s: String) {
+            description("InvalidConfig")
+            display("InvalidConfig Error: {}", s)
+        }
     }
 }
@@ -1,14 +1,18 @@
 //! Handlers
 //!  - Endpoint handlers
 //!
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::{self, BufRead};
 use std::path;
 
+use chrono::{DateTime, TimeZone, Utc};
 use rouille::{self, Request, Response};
 use tera::Context;
 
 use crate::errors::*;
+use crate::health;
+use crate::metrics::{self, Sample};
 use crate::models::{self, CONTENT_TYPES};
 use crate::service::State;
 use crate::{FromRequestBody, FromRequestQuery, ToResponse};
@@ -18,13 +22,85 @@ pub struct NewPasteQueryParams {
     #[serde(rename = "type")]
     pub type_: Option<String>,
     pub ttl_seconds: Option<u32>,
+    // e.g. `90m`, `1h`, `7d`, or seconds
+    pub ttl: Option<String>,
+    // RFC3339 date
+    pub expires_at: Option<String>,
+    // the body is ciphertext encrypted by the client, see `edit.js`
+    pub client_encrypted: Option<bool>,
+    // delete the paste after this many failed decryptions
+    pub max_failed_attempts: Option<u32>,
+    // comma separated recipient names or hex X25519 public keys
+    pub recipients: Option<String>,
+    // gets a long unguessable key and is never indexed
+    pub private: Option<bool>,
+}
+
+/// Parse a duration like `90m`, `1h`, `7d` or `2w` into seconds,
+/// a plain number is taken as seconds
+fn parse_ttl(ttl: &str) -> Result<u32> {
+    let ttl = ttl.trim();
+    let (n, unit) = match ttl.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => ttl.split_at(i),
+        None => (ttl, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => bail_fmt!(
+            ErrorKind::BadRequest,
+            "invalid ttl {:?}, expected a number followed by s, m, h, d or w",
+            ttl
+        ),
+    };
+    let n = n
+        .parse::<u64>()
+        .map_err(|_| format_err!(ErrorKind::BadRequest, "invalid ttl {:?}", ttl))?;
+    // anything this large is over the max anyway
+    Ok(u32::try_from(n.saturating_mul(multiplier)).unwrap_or(u32::MAX))
+}
+
+/// Seconds until a new paste expires, from at most one of
+/// `ttl_seconds`, `ttl` or `expires_at`
+fn requested_ttl(params: &NewPasteQueryParams) -> Result<Option<u32>> {
+    let given = [
+        params.ttl_seconds.is_some(),
+        params.ttl.is_some(),
+        params.expires_at.is_some(),
+    ];
+    if given.iter().filter(|given| **given).count() > 1 {
+        bail_fmt!(
+            ErrorKind::BadRequest,
+            "only one of ttl_seconds, ttl or expires_at can be given"
+        );
+    }
+    if let Some(ref ttl) = params.ttl {
+        return parse_ttl(ttl).map(Some);
+    }
+    if let Some(ref expires_at) = params.expires_at {
+        let expires_at = DateTime::parse_from_rfc3339(expires_at).map_err(|_| {
+            format_err!(
+                ErrorKind::BadRequest,
+                "expires_at must be an RFC3339 date, e.g. 2030-01-01T00:00:00Z"
+            )
+        })?;
+        let secs = expires_at.timestamp() - Utc::now().timestamp();
+        if secs <= 0 {
+            bail_fmt!(ErrorKind::BadRequest, "expires_at must be in the future");
+        }
+        return Ok(Some(u32::try_from(secs).unwrap_or(u32::MAX)));
+    }
+    Ok(params.ttl_seconds)
 }
 
 /// Endpoint for creating a new paste record
 pub fn new_paste(req: &Request, state: &State) -> Result<Response> {
     let paste_params = req.parse_query_params::<NewPasteQueryParams>()?;
+    let paste_ttl_seconds = requested_ttl(&paste_params)?;
     let paste_type = paste_params.type_.unwrap_or_else(|| "auto".to_string());
-    let paste_ttl_seconds = paste_params.ttl_seconds;
     let encryption_key = req.header("x-upaste-encryption-key");
 
     let mut content = match req.header("content-length") {
@@ -74,52 +150,464 @@ pub fn new_paste(req: &Request, state: &State) -> Result<Response> {
     let paste_content = String::from_utf8(content)?;
 
     let new_paste = {
-        let mut conn = state.db.get()?;
         let new_paste = models::NewPaste {
             content: paste_content,
             content_type: paste_type,
+            client_encrypted: paste_params.client_encrypted.unwrap_or(false),
+            max_failed_attempts: paste_params.max_failed_attempts,
+            recipients: paste_params
+                .recipients
+                .as_deref()
+                .map(|r| {
+                    r.split(',')
+                        .map(str::trim)
+                        .filter(|r| !r.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            private: paste_params.private.unwrap_or(false),
         };
-        new_paste.insert(&mut conn, &state.config, paste_ttl_seconds, encryption_key)?
+        new_paste.insert(
+            &state.db,
+            &state.config,
+            paste_ttl_seconds,
+            encryption_key,
+            &state.kdf_pool,
+        )?
+    };
+
+    metrics::inc(&state.metrics.pastes_created);
+    json!({
+        "message": "success",
+        "key": &new_paste.key,
+        "expires_at": new_paste.exp_date.as_ref().map(|d| d.to_rfc3339()),
+    })
+    .to_resp()
+}
+
+/// Address of the client making `req`, from the configured
+/// `client_ip_header` when running behind a proxy
+pub fn client_ip(req: &Request, state: &State) -> String {
+    state
+        .config
+        .client_ip_header
+        .as_deref()
+        .and_then(|header| req.header(header))
+        .and_then(|ips| ips.split(',').next())
+        .map(|ip| ip.trim().to_string())
+        .unwrap_or_else(|| req.remote_addr().ip().to_string())
+}
+
+/// Credentials for decrypting a paste, `enc_key` or else one of the
+/// hex encoded recipient headers
+fn request_unlock(req: &Request, enc_key: Option<&str>) -> Result<Option<models::Unlock>> {
+    if let Some(enc_key) = enc_key {
+        return Ok(Some(models::Unlock::Key(enc_key.to_string())));
+    }
+    let hex_header = |name: &str| {
+        req.header(name)
+            .map(|v| {
+                hex::decode(v.trim())
+                    .map_err(|_| format_err!(ErrorKind::BadRequest, "{} must be hex encoded", name))
+            })
+            .transpose()
     };
+    if let Some(secret) = hex_header("x-upaste-recipient-key")? {
+        return Ok(Some(models::Unlock::RecipientSecret(secret)));
+    }
+    Ok(hex_header("x-upaste-recipient-token")?.map(models::Unlock::RecipientToken))
+}
 
-    json!({"message": "success", "key": &new_paste.key}).to_resp()
+/// Files browsers and crawlers request on their own, which land on `/{key}`
+const KNOWN_ASSETS: &[&str] = &[
+    "favicon.ico",
+    "apple-touch-icon.png",
+    "apple-touch-icon-precomposed.png",
+    "robots.txt",
+    "sitemap.xml",
+    "site.webmanifest",
+    "manifest.json",
+    "browserconfig.xml",
+    "ads.txt",
+    "humans.txt",
+];
+
+/// Whether `key` could be a paste key (see `models::gen_key`), lookups of
+/// anything else are never a guess at one and don't count as misses
+fn looks_like_key(key: &str) -> bool {
+    !KNOWN_ASSETS.contains(&key)
+        && (5..=64).contains(&key.len())
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
 }
 
-fn get_paste(state: &State, key: &str, enc_key: Option<&str>) -> Result<models::Paste> {
-    let mut conn = state.db.get()?;
-    models::Paste::touch_and_get(&mut conn, key, enc_key, &state.config.signing_key)
+/// Count a lookup of a paste (or share link) that doesn't exist towards
+/// `client`'s backoff, logging the keys of clients that look like scanners
+fn record_key_miss(state: &State, client: &str, key: &str) -> Result<()> {
+    metrics::inc(&state.metrics.key_misses);
+    let misses = state.key_misses.record_failure(client)?;
+    if misses >= state.config.key_miss_free_attempts {
+        warn!(
+            "{} lookups of missing pastes from {}, latest {:?}, backing off",
+            misses, client, key
+        );
+    }
+    Ok(())
+}
+
+fn get_paste(
+    req: &Request,
+    state: &State,
+    key: &str,
+    unlock: Option<&models::Unlock>,
+) -> Result<models::Paste> {
+    let client = client_ip(req, state);
+    if let Err(e) = state.key_misses.check(&client) {
+        metrics::inc(&state.metrics.key_miss_throttles);
+        return Err(e);
+    }
+    if let Some(paste) = state.cache.get(key)? {
+        let expired = matches!(paste.exp_date, Some(ref exp_date) if **exp_date <= Utc::now());
+        if !expired {
+            metrics::inc(&state.metrics.pastes_viewed);
+            state.views.record(key)?;
+            return Ok(paste);
+        }
+        // fall through so the expired row is cleaned up
+        state.cache.remove(key)?;
+    }
+    // count decryption attempts before the key is checked, so parallel
+    // guesses can't share a backoff window, and give back those that
+    // didn't fail on a wrong key
+    let attempts = match unlock {
+        Some(_) => match state.lockout.attempt(&client) {
+            Ok(attempts) => Some(attempts),
+            Err(e) => {
+                metrics::inc(&state.metrics.decryption_lockouts);
+                return Err(e);
+            }
+        },
+        None => None,
+    };
+    let result = models::Paste::get(&state.db, key, unlock, &state.config, &state.kdf_pool);
+    let wrong_key =
+        matches!(result, Err(ref e) if matches!(e.kind(), ErrorKind::DecryptionError(_)));
+    if attempts.is_some() && !wrong_key {
+        state.lockout.release(&client)?;
+    }
+    let paste = match result {
+        Ok(paste) => paste,
+        Err(e) => {
+            match e.kind() {
+                // a missing key just means the client is being asked for one
+                ErrorKind::DecryptionError(_) => {
+                    if let Some(failures) = attempts {
+                        metrics::inc(&state.metrics.decryption_failures);
+                        if failures >= state.config.decrypt_free_attempts {
+                            warn!(
+                                "{} failed decryption attempts from {}, backing off",
+                                failures, client
+                            );
+                        }
+                    }
+                }
+                ErrorKind::TooManyAttempts(..) => metrics::inc(&state.metrics.decryption_lockouts),
+                ErrorKind::DoesNotExist(ref s) if s == models::PASTE_EXPIRED => {
+                    metrics::inc(&state.metrics.pastes_expired)
+                }
+                ErrorKind::DoesNotExist(_) if looks_like_key(key) => {
+                    record_key_miss(state, &client, key)?
+                }
+                _ => (),
+            }
+            return Err(e);
+        }
+    };
+    metrics::inc(&state.metrics.pastes_viewed);
+    state.views.record(key)?;
+    state.cache.insert(&paste)?;
+    Ok(paste)
+}
+
+static HTTP_DATE_FMT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Check the request's conditional headers against a paste's
+/// `etag` and `last_modified` date. `If-None-Match` takes precedence
+/// over `If-Modified-Since` when both are present.
+fn is_not_modified(req: &Request, etag: &str, last_modified: &DateTime<Utc>) -> bool {
+    if let Some(if_none_match) = req.header("if-none-match") {
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+    if let Some(if_modified_since) = req.header("if-modified-since") {
+        if let Ok(since) = DateTime::parse_from_rfc2822(if_modified_since) {
+            return last_modified.timestamp() <= since.timestamp();
+        }
+    }
+    false
+}
+
+/// Respond with a `304` if the client's cached copy of the paste is
+/// still current, otherwise build a full response with `build`.
+/// Caching headers are attached to both.
+fn cached_response<F>(req: &Request, paste: &models::Paste, build: F) -> Result<Response>
+where
+    F: FnOnce() -> Result<Response>,
+{
+    let etag = paste.etag();
+    let resp = if is_not_modified(req, &etag, &paste.date_created) {
+        Response::text("").with_status_code(304)
+    } else {
+        build()?
+    };
+    // encrypted and private pastes must never land in a shared cache
+    let cache_control = if paste.nonce.is_some() || paste.client_encrypted || paste.private {
+        "private, no-cache"
+    } else {
+        "public, no-cache"
+    };
+    let resp = if paste.private {
+        resp.with_unique_header("X-Robots-Tag", "noindex")
+    } else {
+        resp
+    };
+    Ok(resp
+        .with_unique_header("ETag", etag)
+        .with_unique_header(
+            "Last-Modified",
+            paste.date_created.format(HTTP_DATE_FMT).to_string(),
+        )
+        .with_unique_header("Cache-Control", cache_control))
 }
 
 #[derive(serde::Serialize)]
 struct PasteContent {
-    pub key: String,
+    // left out when read through a share link
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
     pub content: String,
     pub content_type: String,
+    pub client_encrypted: bool,
+    pub signature: Option<PasteSignature>,
+    // `None` when the paste only expires after not being viewed for a while
+    pub expires_at: Option<String>,
+}
+
+/// A paste's Ed25519 signature and the fields it covers, see `/verify`
+#[derive(serde::Serialize)]
+struct PasteSignature {
+    pub algorithm: &'static str,
+    pub key_id: Option<String>,
+    pub content_sha256: String,
+    pub date_created: i64,
+    pub value: String,
+}
+
+impl PasteSignature {
+    fn from_paste(paste: &models::Paste) -> Option<Self> {
+        paste.ed25519_signature.as_ref().map(|sig| PasteSignature {
+            algorithm: "ed25519",
+            key_id: paste.signing_key_id.clone(),
+            content_sha256: crate::crypto::sha256_hex(&paste.content),
+            date_created: paste.date_created.timestamp(),
+            value: hex::encode(sig),
+        })
+    }
 }
 
 pub fn view_paste_json(req: &Request, state: &State, key: &str) -> Result<Response> {
-    let enc_key = req.header("x-upaste-encryption-key");
-    let paste = get_paste(state, key, enc_key)?;
-    let content = PasteContent {
-        key: paste.key,
-        content: paste.content,
-        content_type: paste.content_type,
+    paste_json(req, state, key, true)
+}
+
+fn paste_json(req: &Request, state: &State, key: &str, reveal_key: bool) -> Result<Response> {
+    let unlock = request_unlock(req, req.header("x-upaste-encryption-key"))?;
+    let paste = get_paste(req, state, key, unlock.as_ref())?;
+    cached_response(req, &paste, || {
+        let content = PasteContent {
+            key: Some(paste.key.clone()).filter(|_| reveal_key),
+            content: paste.content.clone(),
+            content_type: paste.content_type.clone(),
+            client_encrypted: paste.client_encrypted,
+            signature: PasteSignature::from_paste(&paste),
+            expires_at: paste.exp_date.as_ref().map(|d| d.to_rfc3339()),
+        };
+        json!({ "paste": content }).to_resp()
+    })
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RawQueryParams {
+    pub lines: Option<String>,
+}
+
+/// An inclusive byte range, already clamped to the content length
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ByteRange {
+    start: usize,
+    end: usize,
+}
+
+/// Parse a `Range: bytes=...` header against content of length `len`.
+///
+/// Returns `None` if the header is malformed or isn't a byte range, in
+/// which case it should be ignored and the full content served. Returns
+/// an empty `Vec` if no range is satisfiable.
+fn parse_byte_ranges(header: &str, len: usize) -> Option<Vec<ByteRange>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+    let mut ranges = vec![];
+    for spec in specs.split(',') {
+        let (start, end) = spec.trim().split_once('-')?;
+        let (start, end) = match (start.trim(), end.trim()) {
+            ("", "") => return None,
+            ("", suffix) => {
+                // last `n` bytes
+                let n = suffix.parse::<usize>().ok()?;
+                if n == 0 {
+                    continue;
+                }
+                (len.saturating_sub(n), len.saturating_sub(1))
+            }
+            (start, "") => (start.parse::<usize>().ok()?, len.saturating_sub(1)),
+            (start, end) => {
+                let start = start.parse::<usize>().ok()?;
+                let end = end.parse::<usize>().ok()?;
+                if end < start {
+                    return None;
+                }
+                (start, end.min(len.saturating_sub(1)))
+            }
+        };
+        if start >= len {
+            continue;
+        }
+        ranges.push(ByteRange { start, end });
+    }
+    Some(ranges)
+}
+
+/// Build a `206` response for the requested byte ranges, a `416` if none
+/// can be satisfied, or `None` if the full content should be served instead.
+///
+/// tiny_http strips a top-level `Content-Range` header, so partial content
+/// is always sent as `multipart/byteranges`, even for a single range, where
+/// each part carries its own `Content-Range`.
+fn byte_range_response(req: &Request, content: &str, etag: &str) -> Result<Option<Response>> {
+    let range = match req.header("range") {
+        Some(range) => range,
+        None => return Ok(None),
     };
-    json!({ "paste": content }).to_resp()
+    // a stale `If-Range` validator means the client's partial copy is
+    // out of date, so send everything
+    if let Some(if_range) = req.header("if-range") {
+        if if_range.trim() != etag {
+            return Ok(None);
+        }
+    }
+    let bytes = content.as_bytes();
+    let len = bytes.len();
+    let ranges = match parse_byte_ranges(range, len) {
+        Some(ranges) => ranges,
+        None => return Ok(None),
+    };
+
+    let resp = if ranges.is_empty() {
+        Response::text(format!("bytes */{}", len)).with_status_code(416)
+    } else {
+        let boundary = hex::encode(crate::crypto::rand_bytes(16)?);
+        let mut body = vec![];
+        for r in &ranges {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Type: text/plain; charset=utf8\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, r.start, r.end, len
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(&bytes[r.start..=r.end]);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+        Response::from_data(format!("multipart/byteranges; boundary={}", boundary), body)
+            .with_status_code(206)
+    };
+    // partial content must not be re-encoded or the offsets
+    // in each part's `Content-Range` would no longer line up
+    Ok(Some(
+        resp.with_unique_header("Content-Encoding", "identity"),
+    ))
+}
+
+/// Parse a 1-based, inclusive `lines` parameter: `10`, `10-20` or `10-`
+fn parse_line_range(s: &str) -> Result<(usize, Option<usize>)> {
+    let bad = || format_err!(ErrorKind::BadRequest, "invalid lines range: {}", s);
+    let parse = |n: &str| n.trim().parse::<usize>().map_err(|_| bad());
+    let (start, end) = match s.split_once('-') {
+        Some((start, "")) => (parse(start)?, None),
+        Some((start, end)) => (parse(start)?, Some(parse(end)?)),
+        None => {
+            let n = parse(s)?;
+            (n, Some(n))
+        }
+    };
+    if start == 0 || matches!(end, Some(end) if end < start) {
+        return Err(bad().into());
+    }
+    Ok((start, end))
+}
+
+/// Select lines `start..=end` (1-based) from `content`
+fn slice_lines(content: &str, start: usize, end: Option<usize>) -> String {
+    let lines = content.split_inclusive('\n').skip(start - 1);
+    match end {
+        Some(end) => lines.take(end - start + 1).collect(),
+        None => lines.collect(),
+    }
 }
 
 /// Endpoint for returning raw paste content
+///
+/// Supports byte `Range` requests and a `lines` query parameter
+/// for slicing content by line number
 pub fn view_paste_raw(req: &Request, state: &State, key: &str) -> Result<Response> {
-    let enc_key = req.header("x-upaste-encryption-key");
-    match get_paste(state, key, enc_key) {
-        Ok(paste) => Ok(Response::text(paste.content)),
-        Err(e) => match e.kind() {
-            ErrorKind::DecryptionError(_) => json!({
-                "error": "decryption_key_required",
-                "message": "x-upaste-encryption-key header is required"
+    let params = req.parse_query_params::<RawQueryParams>()?;
+    let lines = params.lines.as_deref().map(parse_line_range).transpose()?;
+    let unlock = request_unlock(req, req.header("x-upaste-encryption-key"))?;
+    match get_paste(req, state, key, unlock.as_ref()) {
+        Ok(paste) => cached_response(req, &paste, || {
+            if let Some((start, end)) = lines {
+                return Ok(Response::text(slice_lines(&paste.content, start, end)));
+            }
+            let etag = paste.etag();
+            Ok(match byte_range_response(req, &paste.content, &etag)? {
+                Some(resp) => resp,
+                None => Response::text(paste.content.clone()),
             })
-            .to_resp()
-            .map(|r| r.with_status_code(400)),
+        }),
+        Err(e) => match e.kind() {
+            ErrorKind::DecryptionError(_) => {
+                let conn = state.db.get()?;
+                let recipients = models::Paste::recipient_keys(&conn, key)?;
+                if recipients.is_empty() {
+                    bail_fmt!(
+                        ErrorKind::DecryptionError,
+                        "x-upaste-encryption-key header is required"
+                    );
+                }
+                // only hand out the ephemeral key for a public key the
+                // client already knows, never the list of recipients
+                let public_key = req
+                    .header("x-upaste-recipient-public-key")
+                    .and_then(|k| hex::decode(k.trim()).ok());
+                let ephemeral_public_key = recipients
+                    .iter()
+                    .find(|w| Some(&w.public_key) == public_key.as_ref())
+                    .map(|w| hex::encode(&w.ephemeral_public_key));
+                bail!(ErrorKind::RecipientKeyRequired(ephemeral_public_key))
+            }
             _ => Err(e),
         },
     }
@@ -132,39 +620,67 @@ struct ViewParams {
 
 /// Endpoint for returning formatted paste content
 pub fn view_paste(req: &Request, state: &State, key: &str) -> Result<Response> {
+    render_paste(req, state, key, None)
+}
+
+/// Render a paste, reached through the share link `token` if set, in
+/// which case the page links to the paste through it and hides the key
+fn render_paste(req: &Request, state: &State, key: &str, token: Option<&str>) -> Result<Response> {
     let mut enc_key = req.header("x-upaste-encryption-key").map(String::from);
     if enc_key.is_none() && req.method() == "POST" {
         let params = req.parse_json_body::<ViewParams>()?;
         enc_key = params.encryption_key;
     }
+    let unlock = request_unlock(req, enc_key.as_deref())?;
     let mut context = Context::new();
-    match get_paste(state, key, enc_key.as_deref()) {
+    match token {
+        Some(token) => context.add("json_url", &format!("/s/{}/json", token)),
+        None => {
+            context.add("paste_key", &key);
+            context.add("json_url", &format!("/json/{}", key));
+        }
+    }
+    let noindex = match get_paste(req, state, key, unlock.as_ref()) {
         Ok(paste) => {
-            context.add("paste_key", &paste.key);
             context.add("content", &paste.content);
+            // decrypted in the browser with the key from the link's fragment
+            context.add("client_encrypted", &paste.client_encrypted);
             context.add("content_type", &paste.content_type);
             context.add("content_types", &&CONTENT_TYPES[..]);
+            paste.private
         }
         Err(e) => match e.kind() {
             ErrorKind::DecryptionError(_) => {
-                context.add("paste_key", &key);
                 context.add("content", &"< encrypted >");
                 context.add("content_type", &"");
                 context.add("content_types", &&CONTENT_TYPES[..]);
                 context.add("encrypted", &true);
+                // might be private, and there's nothing to index anyway
+                true
             }
             _ => return Err(e),
         },
-    }
-    let content = state.tera.render("core/edit.html", &context).unwrap();
-    Ok(Response::html(content))
+    };
+    let content = state
+        .tera
+        .render("core/edit.html", &context)
+        .map_err(|e| format!("Error rendering template: {}", e))?;
+    let resp = Response::html(content);
+    Ok(if noindex {
+        resp.with_unique_header("X-Robots-Tag", "noindex")
+    } else {
+        resp
+    })
 }
 
 /// Endpoint for returning landing page
 pub fn home(_req: &Request, state: &State) -> Result<Response> {
     let mut context = Context::new();
     context.add("content_types", &&CONTENT_TYPES[..]);
-    let content = state.tera.render("core/edit.html", &context).unwrap();
+    let content = state
+        .tera
+        .render("core/edit.html", &context)
+        .map_err(|e| format!("Error rendering template: {}", e))?;
     Ok(Response::html(content))
 }
 
@@ -175,14 +691,428 @@ pub fn file(path: &str) -> Result<Response> {
         .extension()
         .and_then(::std::ffi::OsStr::to_str)
         .unwrap_or("");
-    let f = fs::File::open(&path)?;
+    let f = fs::File::open(path)?;
     Ok(Response::from_file(rouille::extension_to_mime(ext), f))
 }
 
-/// Return appinfo/health-check
-pub fn status() -> Result<Response> {
+#[derive(Debug, serde::Deserialize)]
+struct ShareQueryParams {
+    ttl_seconds: Option<u32>,
+}
+
+/// Endpoint for minting a link to a paste that expires after
+/// `ttl_seconds` and doesn't reveal its key, see `Paste::share_link`
+pub fn share_paste(req: &Request, state: &State, key: &str) -> Result<Response> {
+    let params = req.parse_query_params::<ShareQueryParams>()?;
+    let paste = {
+        let conn = state.db.get()?;
+        models::Paste::find(&conn, key)?
+    };
+    let link = paste.share_link(params.ttl_seconds, &state.config)?;
+    let token = link.to_token();
     json!({
-        "hash": include_str!("../commit_hash.txt").trim(),
+        "url": format!("/s/{}", token),
+        "raw_url": format!("/s/{}/raw", token),
+        "json_url": format!("/s/{}/json", token),
+        "expires_at": Utc.timestamp(link.expires, 0).to_rfc3339(),
     })
     .to_resp()
 }
+
+/// Endpoint for revoking every link minted for a paste
+pub fn revoke_share_links(_req: &Request, state: &State, key: &str) -> Result<Response> {
+    {
+        let conn = state.db.get()?;
+        let paste = models::Paste::find(&conn, key)?;
+        models::Paste::revoke_share_links(&conn, paste.id)?;
+    }
+    state.cache.remove(key)?;
+    json!({"message": "success"}).to_resp()
+}
+
+/// Key of the paste behind a share link, counting
+/// links that aren't valid towards the client's backoff
+fn share_link_key(req: &Request, state: &State, token: &str) -> Result<String> {
+    let client = client_ip(req, state);
+    if let Err(e) = state.key_misses.check(&client) {
+        metrics::inc(&state.metrics.key_miss_throttles);
+        return Err(e);
+    }
+    let key = models::ShareLink::from_token(token).and_then(|link| {
+        let conn = state.db.get()?;
+        models::Paste::share_link_key(&conn, &link, &state.config)
+    });
+    if let Err(ref e) = key {
+        if let ErrorKind::DoesNotExist(_) = e.kind() {
+            record_key_miss(state, &client, token)?;
+        }
+    }
+    key
+}
+
+/// Share links expire, so their responses are never cached or indexed
+fn shared_response(resp: Response) -> Response {
+    resp.with_unique_header("Cache-Control", "private, no-cache")
+        .with_unique_header("X-Robots-Tag", "noindex")
+}
+
+/// Endpoint for returning formatted paste content through a share link
+pub fn view_shared_paste(req: &Request, state: &State, token: &str) -> Result<Response> {
+    let key = share_link_key(req, state, token)?;
+    render_paste(req, state, &key, Some(token)).map(shared_response)
+}
+
+/// Endpoint for returning raw paste content through a share link
+pub fn view_shared_paste_raw(req: &Request, state: &State, token: &str) -> Result<Response> {
+    let key = share_link_key(req, state, token)?;
+    view_paste_raw(req, state, &key).map(shared_response)
+}
+
+/// Endpoint for returning paste content as json through a share link
+pub fn view_shared_paste_json(req: &Request, state: &State, token: &str) -> Result<Response> {
+    let key = share_link_key(req, state, token)?;
+    paste_json(req, state, &key, false).map(shared_response)
+}
+
+/// Return the public keys behind paste signatures, by key id
+pub fn public_key(state: &State) -> Result<Response> {
+    let public_key = |key| crate::crypto::ed25519_public_key(key).map(hex::encode);
+    let retired_keys = state
+        .config
+        .retired_signing_keys
+        .iter()
+        .map(|(id, key)| Ok((id.clone(), public_key(key)?)))
+        .collect::<Result<BTreeMap<_, _>>>()?;
+    json!({
+        "algorithm": "ed25519",
+        "key_id": &state.config.signing_key_id,
+        "public_key": public_key(&state.config.signing_key)?,
+        "retired_keys": retired_keys,
+        // what's signed, see `crypto::attestation`
+        "message": "upaste-v1\n{key}\n{content_sha256}\n{date_created}",
+    })
+    .to_resp()
+}
+
+#[derive(serde::Deserialize)]
+struct VerifyParams {
+    key: String,
+    // either the content, or its hex sha256
+    content: Option<String>,
+    content_sha256: Option<String>,
+    date_created: i64,
+    signature: String,
+    key_id: Option<String>,
+}
+
+/// Endpoint for checking a paste signature from `/json/{key}`, without
+/// needing the paste to still exist
+pub fn verify(req: &Request, state: &State) -> Result<Response> {
+    let params = req.parse_json_body::<VerifyParams>()?;
+    let content_sha256 = match (params.content, params.content_sha256) {
+        (Some(content), _) => crate::crypto::sha256_hex(&content),
+        (None, Some(hash)) => hash.to_lowercase(),
+        (None, None) => bail_fmt!(
+            ErrorKind::BadRequest,
+            "content or content_sha256 is required"
+        ),
+    };
+    let sig = hex::decode(&params.signature)
+        .map_err(|_| format_err!(ErrorKind::BadRequest, "signature must be hex encoded"))?;
+    let message = crate::crypto::attestation(&params.key, &content_sha256, params.date_created);
+    let key_id = state
+        .config
+        .signing_keys()
+        .filter(|(id, _)| match params.key_id.as_deref() {
+            Some(key_id) => key_id == *id,
+            None => true,
+        })
+        .find(|(_, key)| {
+            crate::crypto::ed25519_public_key(key)
+                .map(|public_key| crate::crypto::ed25519_verify(&message, &sig, &public_key))
+                .unwrap_or(false)
+        })
+        .map(|(id, _)| id);
+    json!({
+        "valid": key_id.is_some(),
+        "key_id": key_id,
+    })
+    .to_resp()
+}
+
+/// Return appinfo/health-check
+pub fn status(state: &State) -> Result<Response> {
+    health::report(state)?.body.to_resp()
+}
+
+/// Return health-check, failing with a 503 if any check fails
+pub fn ready(state: &State) -> Result<Response> {
+    let report = health::report(state)?;
+    let status = if report.ok { 200 } else { 503 };
+    Ok(report.body.to_resp()?.with_status_code(status))
+}
+
+/// Return prometheus metrics
+pub fn metrics(state: &State) -> Result<Response> {
+    let (paste_count, paste_bytes) = state.metrics.storage_stats(|| {
+        let conn = state.db.get()?;
+        models::Paste::storage_stats(&conn)
+    })?;
+    let pool = state.db.state();
+    let cache = state.cache.stats()?;
+    let kdf = state.kdf_pool.stats();
+    let samples = [
+        Sample {
+            name: "upaste_pastes_stored",
+            help: "Pastes currently stored",
+            kind: "gauge",
+            value: paste_count as f64,
+        },
+        Sample {
+            name: "upaste_bytes_stored",
+            help: "Bytes of paste content currently stored",
+            kind: "gauge",
+            value: paste_bytes as f64,
+        },
+        Sample {
+            name: "upaste_db_pool_connections",
+            help: "Open database connections",
+            kind: "gauge",
+            value: pool.connections as f64,
+        },
+        Sample {
+            name: "upaste_db_pool_idle_connections",
+            help: "Idle database connections",
+            kind: "gauge",
+            value: pool.idle_connections as f64,
+        },
+        Sample {
+            name: "upaste_db_pool_max_connections",
+            help: "Maximum database connections",
+            kind: "gauge",
+            value: state.db.max_size() as f64,
+        },
+        Sample {
+            name: "upaste_paste_cache_entries",
+            help: "Pastes held in the in-memory cache",
+            kind: "gauge",
+            value: cache.size as f64,
+        },
+        Sample {
+            name: "upaste_paste_cache_hits_total",
+            help: "Paste cache hits",
+            kind: "counter",
+            value: cache.hits as f64,
+        },
+        Sample {
+            name: "upaste_paste_cache_misses_total",
+            help: "Paste cache misses",
+            kind: "counter",
+            value: cache.misses as f64,
+        },
+        Sample {
+            name: "upaste_lockout_clients",
+            help: "Clients backing off after failed decryptions",
+            kind: "gauge",
+            value: state.lockout.clients()? as f64,
+        },
+        Sample {
+            name: "upaste_kdf_queue_depth",
+            help: "Key derivations waiting on a worker",
+            kind: "gauge",
+            value: kdf.queued as f64,
+        },
+        Sample {
+            name: "upaste_kdf_rejected_total",
+            help: "Key derivations refused with a full queue",
+            kind: "counter",
+            value: kdf.rejected as f64,
+        },
+    ];
+    let body = state.metrics.render(&samples)?;
+    Ok(Response::from_data("text/plain; version=0.0.4", body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_bad_request(result: Result<u32>) -> bool {
+        matches!(result, Err(ref e) if matches!(e.kind(), ErrorKind::BadRequest(_)))
+    }
+
+    #[test]
+    fn ttl_units() {
+        assert_eq!(parse_ttl("90").unwrap(), 90);
+        assert_eq!(parse_ttl("45s").unwrap(), 45);
+        assert_eq!(parse_ttl("90m").unwrap(), 90 * 60);
+        assert_eq!(parse_ttl(" 1h ").unwrap(), 60 * 60);
+        assert_eq!(parse_ttl("7d").unwrap(), 7 * 24 * 60 * 60);
+        assert_eq!(parse_ttl("2w").unwrap(), 14 * 24 * 60 * 60);
+    }
+
+    #[test]
+    fn ttl_saturates() {
+        assert_eq!(parse_ttl("99999999w").unwrap(), u32::MAX);
+        assert_eq!(parse_ttl("4294967296").unwrap(), u32::MAX);
+        // too large to even parse
+        assert!(is_bad_request(parse_ttl("99999999999999999999999")));
+    }
+
+    #[test]
+    fn ttl_invalid() {
+        for ttl in ["", "h", "1y", "1.5h", "-1h", "1 h", "1hh"] {
+            assert!(is_bad_request(parse_ttl(ttl)), "{:?}", ttl);
+        }
+    }
+
+    #[test]
+    fn line_ranges() {
+        assert_eq!(parse_line_range("3").unwrap(), (3, Some(3)));
+        assert_eq!(parse_line_range("2-4").unwrap(), (2, Some(4)));
+        assert_eq!(parse_line_range("2-").unwrap(), (2, None));
+        for lines in ["", "0", "0-2", "4-2", "-2", "a-b"] {
+            assert!(parse_line_range(lines).is_err(), "{:?}", lines);
+        }
+    }
+
+    #[test]
+    fn slicing_lines() {
+        let content = "one\ntwo\nthree\n";
+        assert_eq!(slice_lines(content, 2, Some(2)), "two\n");
+        assert_eq!(slice_lines(content, 2, None), "two\nthree\n");
+        assert_eq!(slice_lines(content, 3, Some(10)), "three\n");
+        assert_eq!(slice_lines(content, 5, None), "");
+    }
+
+    fn range_request(range: &str, if_range: Option<&str>) -> Request {
+        let mut headers = vec![("Range".to_string(), range.to_string())];
+        if let Some(if_range) = if_range {
+            headers.push(("If-Range".to_string(), if_range.to_string()));
+        }
+        Request::fake_http("GET", "/raw/abc", headers, vec![])
+    }
+
+    fn read_body(resp: Response) -> String {
+        let (mut reader, _) = resp.data.into_reader_and_size();
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        body
+    }
+
+    #[test]
+    fn byte_ranges() {
+        let range = |start, end| ByteRange { start, end };
+        assert_eq!(parse_byte_ranges("bytes=0-4", 10).unwrap(), [range(0, 4)]);
+        assert_eq!(parse_byte_ranges("bytes=5-", 10).unwrap(), [range(5, 9)]);
+        assert_eq!(parse_byte_ranges("bytes=-3", 10).unwrap(), [range(7, 9)]);
+        assert_eq!(parse_byte_ranges("bytes=8-20", 10).unwrap(), [range(8, 9)]);
+        assert_eq!(
+            parse_byte_ranges("bytes=0-0, 2-3", 10).unwrap(),
+            [range(0, 0), range(2, 3)]
+        );
+        assert!(parse_byte_ranges("bytes=10-", 10).unwrap().is_empty());
+        for header in ["items=0-1", "bytes=-", "bytes=4-2", "bytes=a-b"] {
+            assert!(parse_byte_ranges(header, 10).is_none(), "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn partial_content() {
+        let req = range_request("bytes=0-4", Some("tag"));
+        let resp = byte_range_response(&req, "hello world", "tag")
+            .unwrap()
+            .unwrap();
+        assert_eq!(resp.status_code, 206);
+        let body = read_body(resp);
+        assert!(body.contains("Content-Range: bytes 0-4/11\r\n\r\nhello\r\n"));
+        assert_eq!(body.matches("Content-Range").count(), 1);
+
+        let req = range_request("bytes=0-4,-5", None);
+        let resp = byte_range_response(&req, "hello world", "tag")
+            .unwrap()
+            .unwrap();
+        assert_eq!(resp.status_code, 206);
+        let body = read_body(resp);
+        assert!(body.contains("Content-Range: bytes 0-4/11\r\n\r\nhello\r\n"));
+        assert!(body.contains("Content-Range: bytes 6-10/11\r\n\r\nworld\r\n"));
+    }
+
+    #[test]
+    fn unsatisfiable_range() {
+        let req = range_request("bytes=20-30", None);
+        let resp = byte_range_response(&req, "hello world", "tag")
+            .unwrap()
+            .unwrap();
+        assert_eq!(resp.status_code, 416);
+        assert_eq!(read_body(resp), "bytes */11");
+    }
+
+    #[test]
+    fn full_content_for_stale_or_bad_ranges() {
+        let stale = range_request("bytes=0-4", Some("old"));
+        assert!(byte_range_response(&stale, "hello world", "tag")
+            .unwrap()
+            .is_none());
+        let malformed = range_request("bytes=4-2", None);
+        assert!(byte_range_response(&malformed, "hello world", "tag")
+            .unwrap()
+            .is_none());
+    }
+
+    fn state(dir: &path::Path) -> State {
+        let config = crate::Config::with_env(&[
+            ("KEY_MISS_FREE_ATTEMPTS", "3"),
+            ("KEY_MISS_BACKOFF_MAX_SECONDS", "60"),
+        ]);
+        crate::service::migrated_state(dir, config)
+    }
+
+    fn lookup(state: &State, client: &str, key: &str) -> Result<models::Paste> {
+        let from = format!("{}:4000", client).parse().unwrap();
+        let req = Request::fake_http_from(from, "GET", format!("/{}", key), vec![], vec![]);
+        get_paste(&req, state, key, None)
+    }
+
+    fn is_throttled(result: Result<models::Paste>) -> bool {
+        matches!(result, Err(ref e) if matches!(e.kind(), ErrorKind::TooManyAttempts(..)))
+    }
+
+    #[test]
+    fn key_miss_threshold() {
+        let dir = std::env::temp_dir().join(format!("upaste-key-misses-{}", std::process::id()));
+        let state = state(&dir);
+
+        // browsers asking for icons and crawlers for sitemaps aren't guessing
+        for _ in 0..10 {
+            for key in [
+                "favicon.ico",
+                "apple-touch-icon.png",
+                "wp-login.php",
+                "robots.txt",
+            ] {
+                assert!(!is_throttled(lookup(&state, "10.0.0.1", key)));
+            }
+        }
+        assert!(state.key_misses.check("10.0.0.1").is_ok());
+
+        // misses up to the threshold are free, the next lookup backs off
+        for key in ["aaaaa", "bbbbb", "ccccc"] {
+            match lookup(&state, "10.0.0.2", key) {
+                Err(e) => assert!(matches!(e.kind(), ErrorKind::DoesNotExist(_))),
+                Ok(_) => panic!("expected a miss"),
+            }
+        }
+        assert!(is_throttled(lookup(&state, "10.0.0.2", "ddddd")));
+        // other clients aren't held back
+        assert!(!is_throttled(lookup(&state, "10.0.0.3", "ddddd")));
+        assert_eq!(
+            state
+                .metrics
+                .key_misses
+                .load(std::sync::atomic::Ordering::Relaxed),
+            4
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
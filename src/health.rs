@@ -0,0 +1,212 @@
+/*!
+Health checks
+
+Backs `/status` and `/status/ready`
+*/
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+
+use crate::errors::*;
+use crate::service::{self, State};
+
+/// Outcome of the most recent stale paste sweeps
+#[derive(Default)]
+pub struct SweeperStatus {
+    pub last_run: Option<DateTime<Utc>>,
+    pub last_error: Option<(DateTime<Utc>, String)>,
+}
+
+/// The sweeper is considered stuck if it misses this many runs
+const SWEEPER_MAX_MISSED_RUNS: i64 = 3;
+
+/// Result of all health checks
+pub struct Report {
+    pub ok: bool,
+    pub body: serde_json::Value,
+}
+
+fn check_database(state: &State) -> Result<()> {
+    let conn = state.db.get()?;
+    conn.query_row("select 1", rusqlite::NO_PARAMS, |_| Ok(()))?;
+    Ok(())
+}
+
+/// 14-digit timestamp prefix of a migration tag
+///
+/// Older migrant versions recorded tags truncated at the first
+/// underscore after the timestamp, so only the stamp is compared
+fn migration_stamp(tag: &str) -> &str {
+    tag.split('_').next().unwrap_or(tag)
+}
+
+/// Migrations available on disk that haven't been applied
+fn check_migrations(state: &State) -> Result<Vec<String>> {
+    let conn = state.db.get()?;
+    let mut stmt = conn.prepare("select tag from __migrant_migrations")?;
+    let applied = stmt
+        .query_map(rusqlite::NO_PARAMS, |row| row.get::<_, String>(0))?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let applied = applied
+        .iter()
+        .map(|tag| migration_stamp(tag))
+        .collect::<HashSet<_>>();
+    let dir = service::migrant_config()?.migration_location()?;
+    let mut pending = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|tag| !applied.contains(migration_stamp(tag)))
+        .collect::<Vec<_>>();
+    pending.sort();
+    Ok(pending)
+}
+
+/// Free bytes available to unprivileged users on the filesystem holding `path`
+#[cfg(unix)]
+fn disk_free_bytes(path: &Path) -> Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| "invalid disk path")?;
+    // SAFETY: `statvfs` only writes into the zeroed struct we own
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn disk_free_bytes(_path: &Path) -> Result<u64> {
+    bail!("disk space check unsupported on this platform")
+}
+
+/// Free bytes on the filesystem holding the pooled database
+fn check_disk(state: &State) -> Result<u64> {
+    let conn = state.db.get()?;
+    // empty for in-memory databases
+    let db_path: String = conn.query_row(
+        "select file from pragma_database_list where name = 'main'",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    let dir = Path::new(&db_path)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    disk_free_bytes(dir)
+}
+
+/// Run all checks against the running service
+pub fn report(state: &State) -> Result<Report> {
+    let database = match check_database(state) {
+        Ok(()) => json!({ "ok": true }),
+        Err(e) => json!({ "ok": false, "error": e.to_string() }),
+    };
+
+    let migrations = match check_migrations(state) {
+        Ok(pending) => json!({
+            "ok": pending.is_empty(),
+            "pending": pending,
+        }),
+        Err(e) => json!({ "ok": false, "error": e.to_string() }),
+    };
+
+    let sweeper = {
+        let status = state.sweeper.lock().map_err(|e| {
+            format_err!(ErrorKind::SyncPoison, "sweeper status lock poisoned: {}", e)
+        })?;
+        let max_age = chrono::Duration::seconds(
+            service::SWEEP_INTERVAL_SECONDS as i64 * SWEEPER_MAX_MISSED_RUNS,
+        );
+        // give a freshly started sweeper time for its first run
+        let last_seen = status.last_run.unwrap_or(state.started);
+        let stuck = Utc::now() - last_seen > max_age;
+        let failing = match (&status.last_run, &status.last_error) {
+            (Some(run), Some((errored, _))) => errored >= run,
+            (None, Some(_)) => true,
+            _ => false,
+        };
+        json!({
+            "ok": !stuck && !failing,
+            "last_run": status.last_run.map(|d| d.to_rfc3339()),
+            "last_error": status.last_error.as_ref().map(|(_, e)| e),
+            "last_error_at": status.last_error.as_ref().map(|(d, _)| d.to_rfc3339()),
+        })
+    };
+
+    let disk = match check_disk(state) {
+        Ok(free) => json!({ "ok": free >= state.config.min_free_disk_bytes }),
+        Err(e) => json!({ "ok": false, "error": e.to_string() }),
+    };
+
+    let checks = json!({
+        "database": database,
+        "migrations": migrations,
+        "sweeper": sweeper,
+        "disk": disk,
+    });
+    let ok = checks
+        .as_object()
+        .map(|checks| checks.values().all(|check| check["ok"] == true))
+        .unwrap_or(false);
+
+    let body = json!({
+        "ok": ok,
+        "hash": state.config.version,
+        "version": state.config.version,
+        "uptime_seconds": (Utc::now() - state.started).num_seconds(),
+        "checks": checks,
+        "paste_cache": state.cache.stats()?,
+        "kdf_pool": state.kdf_pool.stats(),
+    });
+    Ok(Report { ok, body })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::Resources;
+
+    #[test]
+    fn ready_once_migrated() {
+        let dir = std::env::temp_dir().join(format!("upaste-health-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("upaste");
+        let settings = migrant_lib::Settings::configure_sqlite()
+            .database_path(&db_path)
+            .unwrap()
+            .migration_location(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+            .unwrap()
+            .build()
+            .unwrap();
+        let migrant = migrant_lib::Config::with_settings(&settings);
+        migrant.setup().unwrap();
+
+        let manager = r2d2_sqlite::SqliteConnectionManager::file(&db_path);
+        let db = r2d2::Pool::builder().max_size(1).build(manager).unwrap();
+        let config = crate::Config::with_env(&[]);
+        let state = std::sync::Arc::new(Resources::new(tera::Tera::default(), db, config).unwrap());
+        let before = report(&state).unwrap();
+        assert!(!before.ok);
+        assert!(!before.body["checks"]["migrations"]["pending"]
+            .as_array()
+            .unwrap()
+            .is_empty());
+
+        migrant_lib::Migrator::with_config(&migrant.reload().unwrap())
+            .direction(migrant_lib::Direction::Up)
+            .all(true)
+            .show_output(false)
+            .apply()
+            .unwrap();
+        let after = report(&state).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(after.ok, "{}", after.body);
+        assert_eq!(after.body["checks"]["migrations"]["pending"], json!([]));
+    }
+}
@@ -13,16 +13,28 @@ extern crate log;
 #[macro_use]
 extern crate rouille;
 
+// error_chain's generated impls check a cfg this compiler doesn't know about
+#[allow(unexpected_cfgs)]
 pub mod errors;
 #[macro_use]
 pub mod macros;
 
 pub mod admin;
+pub mod cache;
+pub mod config;
 mod crypto;
 pub mod handlers;
+pub mod health;
+pub mod lockout;
+pub mod logging;
+pub mod metrics;
 pub mod models;
 pub mod service;
+pub mod shutdown;
+pub mod views;
+pub mod workers;
 
+pub use config::Config;
 use errors::*;
 use std::io::Read;
 
@@ -104,56 +116,3 @@ impl ToResponse for serde_json::Value {
         Ok(resp)
     }
 }
-
-fn env_or(k: &str, default: &str) -> String {
-    std::env::var(k).unwrap_or_else(|_| default.to_string())
-}
-
-#[derive(Clone)]
-pub struct Config {
-    pub version: String,
-
-    // host to listen on, defaults to localhost
-    pub host: String,
-    pub port: u16,
-
-    pub log_level: String,
-
-    // key used for encrypting protected pastes
-    pub encryption_key: String,
-    // key used to derive signature of paste content
-    pub signing_key: String,
-
-    pub max_paste_bytes: usize,
-    pub max_paste_age_seconds: i64,
-}
-impl Config {
-    pub fn load() -> Self {
-        let version = std::fs::File::open("commit_hash.txt")
-            .map(|mut f| {
-                let mut s = String::new();
-                f.read_to_string(&mut s).expect("Error reading commit_hasg");
-                s
-            })
-            .unwrap_or_else(|_| "unknown".to_string());
-        Self {
-            version,
-            host: env_or("HOST", "localhost"),
-            port: env_or("PORT", "3003").parse().expect("invalid port"),
-            log_level: env_or("LOG_LEVEL", "INFO"),
-            encryption_key: env_or("ENCRYPTION_KEY", "01234567890123456789012345678901"),
-            signing_key: env_or("SIGNING_KEY", "01234567890123456789012345678901"),
-            max_paste_bytes: env_or("MAX_PASTE_BYTES", "1000000")
-                .parse()
-                .unwrap_or_else(|e| panic!("invalid MAX_PASTE_BYTES {:?}", e)),
-            // 60 * 60 * 24 * 30
-            max_paste_age_seconds: env_or("MAX_PASTE_AGE_SECONDS", "2592000")
-                .parse()
-                .unwrap_or_else(|e| panic!("invalid MAX_PASTE_AGE_SECONDS {:?}", e)),
-        }
-    }
-
-    pub fn host(&self) -> String {
-        format!("{}:{}", self.host, self.port)
-    }
-}
@@ -0,0 +1,211 @@
+/*!
+Backoff on failed decryption attempts and paste key misses
+
+Encrypted pastes are only as strong as their user key, so repeated
+wrong guesses are slowed down exponentially. Failures are counted per
+paste in the database, see `models::Paste::get`, and per client here.
+
+Short paste keys can be enumerated, so clients looking up many keys
+that don't exist are slowed down the same way.
+*/
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+/// Exponential backoff after consecutive failures
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    free_attempts: u32,
+    max_seconds: u64,
+    reason: &'static str,
+}
+
+impl Backoff {
+    pub fn decryption(config: &crate::Config) -> Self {
+        Self {
+            free_attempts: config.decrypt_free_attempts,
+            max_seconds: config.decrypt_backoff_max_seconds,
+            reason: "too many failed decryption attempts",
+        }
+    }
+
+    pub fn key_misses(config: &crate::Config) -> Self {
+        Self {
+            free_attempts: config.key_miss_free_attempts,
+            max_seconds: config.key_miss_backoff_max_seconds,
+            reason: "too many requests for pastes that don't exist",
+        }
+    }
+
+    /// How long to wait after `failures` consecutive failures
+    pub fn delay(&self, failures: u32) -> Duration {
+        if failures < self.free_attempts {
+            return Duration::from_secs(0);
+        }
+        let secs = 1u64
+            .checked_shl(failures - self.free_attempts)
+            .unwrap_or(u64::MAX);
+        Duration::from_secs(secs.min(self.max_seconds))
+    }
+
+    /// Fail with `TooManyAttempts` if the last of `failures` was
+    /// less than its backoff delay ago
+    pub fn check(&self, failures: u32, elapsed: Duration) -> Result<()> {
+        let delay = self.delay(failures);
+        if elapsed < delay {
+            let retry_after = (delay - elapsed).as_secs() + 1;
+            bail!(ErrorKind::TooManyAttempts(self.reason.into(), retry_after));
+        }
+        Ok(())
+    }
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+/// Consecutive failures per client address, forgotten once
+/// a client hasn't failed for the max backoff
+pub struct ClientLockout {
+    backoff: Backoff,
+    clients: Mutex<HashMap<String, Failures>>,
+}
+
+impl ClientLockout {
+    pub fn new(backoff: Backoff) -> Self {
+        Self {
+            backoff,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, HashMap<String, Failures>>> {
+        self.clients.lock().map_err(|e| {
+            format_err!(ErrorKind::SyncPoison, "client lockout lock poisoned: {}", e).into()
+        })
+    }
+
+    /// Fail with `TooManyAttempts` if `client` is still backing off
+    pub fn check(&self, client: &str) -> Result<()> {
+        match self.lock()?.get(client) {
+            Some(failures) => self.backoff.check(failures.count, failures.last.elapsed()),
+            None => Ok(()),
+        }
+    }
+
+    /// Count an attempt by `client` before it's known to have failed,
+    /// returning its failure count. Fails with `TooManyAttempts` while it's
+    /// still backing off, so parallel attempts can't all pass one `check`.
+    /// Call `release` if the attempt turns out not to be a failure.
+    pub fn attempt(&self, client: &str) -> Result<u32> {
+        let mut clients = self.lock()?;
+        if let Some(failures) = clients.get(client) {
+            self.backoff
+                .check(failures.count, failures.last.elapsed())?;
+        }
+        let failures = clients.entry(client.to_string()).or_insert(Failures {
+            count: 0,
+            last: Instant::now(),
+        });
+        failures.count += 1;
+        failures.last = Instant::now();
+        Ok(failures.count)
+    }
+
+    /// Give back an attempt counted by `attempt` that didn't fail
+    pub fn release(&self, client: &str) -> Result<()> {
+        if let Some(failures) = self.lock()?.get_mut(client) {
+            failures.count = failures.count.saturating_sub(1);
+        }
+        Ok(())
+    }
+
+    /// Record a failed attempt by `client`, returning its failure count.
+    /// Successes never reset a client since any paste of its own would do.
+    pub fn record_failure(&self, client: &str) -> Result<u32> {
+        let mut clients = self.lock()?;
+        let failures = clients.entry(client.to_string()).or_insert(Failures {
+            count: 0,
+            last: Instant::now(),
+        });
+        failures.count += 1;
+        failures.last = Instant::now();
+        Ok(failures.count)
+    }
+
+    /// Number of clients currently tracked
+    pub fn clients(&self) -> Result<usize> {
+        Ok(self.lock()?.len())
+    }
+
+    /// Forget clients that haven't failed within the max backoff,
+    /// returning the number of clients still tracked
+    pub fn prune(&self) -> Result<usize> {
+        let max = Duration::from_secs(self.backoff.max_seconds);
+        let mut clients = self.lock()?;
+        clients.retain(|_, failures| failures.last.elapsed() < max);
+        Ok(clients.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(max_seconds: u64) -> Backoff {
+        Backoff {
+            free_attempts: 3,
+            max_seconds,
+            reason: "test",
+        }
+    }
+
+    #[test]
+    fn backoff_schedule() {
+        let backoff = backoff(60);
+        let delays = (0..10)
+            .map(|failures| backoff.delay(failures).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [0, 0, 0, 1, 2, 4, 8, 16, 32, 60]);
+        assert_eq!(backoff.delay(u32::MAX).as_secs(), 60);
+
+        assert!(backoff.check(2, Duration::from_secs(0)).is_ok());
+        assert!(backoff.check(5, Duration::from_secs(4)).is_ok());
+        match backoff.check(5, Duration::from_millis(1500)) {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::TooManyAttempts(_, 3))),
+            Ok(()) => panic!("expected a backoff"),
+        }
+    }
+
+    #[test]
+    fn client_attempts() {
+        let lockout = ClientLockout::new(backoff(60));
+        for n in 1..=3 {
+            assert_eq!(lockout.attempt("a").unwrap(), n);
+        }
+        // the fourth has to wait a second, other clients don't
+        assert!(lockout.attempt("a").is_err());
+        assert!(lockout.check("a").is_err());
+        assert!(lockout.check("b").is_ok());
+        lockout.release("a").unwrap();
+        assert!(lockout.check("a").is_ok());
+        assert_eq!(lockout.record_failure("a").unwrap(), 3);
+    }
+
+    #[test]
+    fn prune() {
+        let lockout = ClientLockout::new(backoff(60));
+        lockout.record_failure("a").unwrap();
+        lockout.record_failure("b").unwrap();
+        assert_eq!(lockout.prune().unwrap(), 2);
+
+        // nothing fails within a max backoff of zero
+        let lockout = ClientLockout::new(backoff(0));
+        lockout.record_failure("a").unwrap();
+        assert_eq!(lockout.prune().unwrap(), 0);
+        assert!(lockout.check("a").is_ok());
+    }
+}
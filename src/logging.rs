@@ -0,0 +1,121 @@
+/*!
+Logging
+ - `text` or `json` formatted log records, selected by `Config::log_format`
+ - structured key-values attached to records are emitted as
+   `key=value` pairs in `text` and as top-level fields in `json`
+ - records logged while handling a request are tagged with its `request_id`,
+   including those logged by its jobs on a `workers::WorkerPool`
+*/
+use std::cell::RefCell;
+use std::io::Write;
+
+use chrono::{Local, Utc};
+use log::kv::{self, Key, Value};
+
+thread_local! {
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Tags all records logged on the current thread with a `request_id`
+/// until dropped
+pub struct RequestScope {
+    _private: (),
+}
+impl RequestScope {
+    pub fn enter(request_id: &str) -> Self {
+        REQUEST_ID.with(|id| *id.borrow_mut() = Some(request_id.to_string()));
+        Self { _private: () }
+    }
+}
+impl Drop for RequestScope {
+    fn drop(&mut self) {
+        REQUEST_ID.with(|id| *id.borrow_mut() = None);
+    }
+}
+
+/// `request_id` of the request being handled on the current thread
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+/// Collects a record's key-values as json values
+#[derive(Default)]
+struct JsonFields(serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> kv::VisitSource<'kvs> for JsonFields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.insert(key.to_string(), to_json(&value));
+        Ok(())
+    }
+}
+
+/// Collects a record's key-values as ` key=value` pairs
+#[derive(Default)]
+struct TextFields(String);
+
+impl<'kvs> kv::VisitSource<'kvs> for TextFields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push_str(&format!(" {}={}", key, value));
+        Ok(())
+    }
+}
+
+/// Convert a log value to json, keeping strings as strings and
+/// recovering numbers, bools and `None` from their display form
+fn to_json(value: &Value) -> serde_json::Value {
+    if let Some(s) = value.to_borrowed_str() {
+        return json!(s);
+    }
+    let s = value.to_string();
+    if s == "None" {
+        return serde_json::Value::Null;
+    }
+    serde_json::from_str::<serde_json::Value>(&s)
+        .ok()
+        .filter(|v| v.is_number() || v.is_boolean())
+        .unwrap_or_else(|| json!(s))
+}
+
+/// Initialize the global logger
+///
+/// The log level is set from `Config::log_level`, e.g. `LOG_LEVEL=info upaste serve`
+pub fn init(config: &crate::Config) {
+    let mut builder = env_logger::Builder::new();
+    if config.log_format == "json" {
+        builder.format(|buf, record| {
+            let mut fields = JsonFields::default();
+            record.key_values().visit(&mut fields).ok();
+            let mut line = serde_json::Map::new();
+            line.insert("ts".into(), json!(Utc::now().to_rfc3339()));
+            line.insert("level".into(), json!(record.level().to_string()));
+            line.insert(
+                "module".into(),
+                json!(record.module_path().unwrap_or("<unknown>")),
+            );
+            line.insert("msg".into(), json!(record.args().to_string()));
+            line.extend(fields.0);
+            if let Some(request_id) = current_request_id() {
+                line.insert("request_id".into(), json!(request_id));
+            }
+            writeln!(buf, "{}", serde_json::Value::Object(line))
+        });
+    } else {
+        builder.format(|buf, record| {
+            let mut fields = TextFields::default();
+            record.key_values().visit(&mut fields).ok();
+            if let Some(request_id) = current_request_id() {
+                fields.0.push_str(&format!(" request_id={}", request_id));
+            }
+            writeln!(
+                buf,
+                "{} [{}] - [{}] -> {}{}",
+                Local::now().format("%Y-%m-%d_%H:%M:%S"),
+                record.level(),
+                record.module_path().unwrap_or("<unknown>"),
+                record.args(),
+                fields.0,
+            )
+        });
+    }
+    builder.parse(&config.log_level).init();
+}
@@ -1,4 +1,6 @@
 #![recursion_limit = "1024"]
+// error_chain's generated impls check a cfg this compiler doesn't know about
+#![allow(unexpected_cfgs)]
 #[macro_use]
 extern crate error_chain;
 extern crate upaste_server;
@@ -7,6 +9,7 @@ extern crate clap;
 
 use std::env;
 use upaste_server::admin;
+use upaste_server::config;
 use upaste_server::service;
 
 use clap::{App, Arg, SubCommand};
@@ -22,8 +25,27 @@ pub fn run() -> Result<()> {
     let matches = App::new("upaste")
         .version(crate_version!())
         .about("uPaste Server")
+        .arg(Arg::with_name("config")
+             .long("config")
+             .takes_value(true)
+             .global(true)
+             .help("TOML config file, overridden by env vars. Defaults to $UPASTE_CONFIG"))
         .subcommand(SubCommand::with_name("serve")
-                    .about("Initialize Server"))
+                    .about("Initialize Server")
+                    .arg(Arg::with_name("host")
+                         .long("host")
+                         .takes_value(true)
+                         .help("Host to listen on, overrides config and HOST"))
+                    .arg(Arg::with_name("port")
+                         .long("port")
+                         .takes_value(true)
+                         .help("Port to listen on, overrides config and PORT")))
+        .subcommand(SubCommand::with_name("config")
+                    .about("Config functions")
+                    .subcommand(SubCommand::with_name("check")
+                        .about("Validate the config file and env vars"))
+                    .subcommand(SubCommand::with_name("show")
+                        .about("Print the effective config and where each value came from, with secrets redacted")))
         .subcommand(SubCommand::with_name("admin")
                     .about("Admin functions")
                     .subcommand(SubCommand::with_name("database")
@@ -49,16 +71,75 @@ pub fn run() -> Result<()> {
                         .arg(Arg::with_name("no-confirm")
                              .long("no-confirm")
                              .takes_value(false)
-                             .help("Auto-confirm/skip any confirmation checks"))))
+                             .help("Auto-confirm/skip any confirmation checks")))
+                    .subcommand(SubCommand::with_name("resign")
+                        .about("Re-sign pastes with the current SIGNING_KEY, see SIGNING_KEY_ID and RETIRED_SIGNING_KEYS")
+                        .arg(Arg::with_name("database")
+                             .long("db-path")
+                             .takes_value(true)
+                             .help("Sqlite database path to connect to")))
+                    .subcommand(SubCommand::with_name("reencrypt")
+                        .about("Encrypt pastes at rest with the current ENCRYPTION_KEY, including pastes under RETIRED_ENCRYPTION_KEYS")
+                        .arg(Arg::with_name("database")
+                             .long("db-path")
+                             .takes_value(true)
+                             .help("Sqlite database path to connect to")))
+                    .subcommand(SubCommand::with_name("recipients")
+                        .about("Manage the named public keys pastes can be encrypted to with `/new?recipients=`")
+                        .arg(Arg::with_name("database")
+                             .long("db-path")
+                             .takes_value(true)
+                             .global(true)
+                             .help("Sqlite database path to connect to"))
+                        .subcommand(SubCommand::with_name("add")
+                            .about("Add a recipient")
+                            .arg(Arg::with_name("name")
+                                 .required(true)
+                                 .help("Name to encrypt pastes to"))
+                            .arg(Arg::with_name("public-key")
+                                 .required(true)
+                                 .help("Hex encoded X25519 public key")))
+                        .subcommand(SubCommand::with_name("remove")
+                            .about("Remove a recipient, pastes already encrypted to them are unaffected")
+                            .arg(Arg::with_name("name")
+                                 .required(true)
+                                 .help("Recipient name")))
+                        .subcommand(SubCommand::with_name("list")
+                            .about("List recipients and their public keys"))
+                        .subcommand(SubCommand::with_name("keygen")
+                            .about("Generate a hex encoded X25519 secret and public key pair")))
+                    .subcommand(SubCommand::with_name("keygen")
+                        .about("Generate a random hex encoded key for ENCRYPTION_KEY or SIGNING_KEY")
+                        .arg(Arg::with_name("bytes")
+                             .long("bytes")
+                             .takes_value(true)
+                             .help("Number of random bytes, defaults to 32"))
+                        .arg(Arg::with_name("out")
+                             .long("out")
+                             .takes_value(true)
+                             .help("Write the key to a new file readable only by its owner, e.g. for SIGNING_KEY_FILE"))))
         .get_matches();
 
-    if matches.subcommand_matches("serve").is_some() {
-        service::start()?;
+    let config_path = config::config_path(matches.value_of("config"));
+
+    if let Some(serve_matches) = matches.subcommand_matches("serve") {
+        let mut raw = config::Config::load_raw(config_path.as_deref())?;
+        for flag in &["host", "port"] {
+            if let Some(value) = serve_matches.value_of(flag) {
+                raw.set_flag(flag, value)?;
+            }
+        }
+        service::start(raw.build()?)?;
+        return Ok(());
+    }
+
+    if let Some(config_matches) = matches.subcommand_matches("config") {
+        config::handle(config_matches, config_path.as_deref())?;
         return Ok(());
     }
 
     if let Some(admin_matches) = matches.subcommand_matches("admin") {
-        admin::handle(admin_matches)?;
+        admin::handle(admin_matches, config_path.as_deref())?;
         return Ok(());
     }
 
@@ -0,0 +1,228 @@
+/*!
+Prometheus metrics
+
+Counters are updated in-process and rendered in the prometheus
+text exposition format by `handlers::metrics`.
+*/
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time;
+
+use crate::errors::*;
+
+/// Upper bounds (seconds) of the request latency histogram buckets
+static LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    // non-cumulative counts per bucket, `+Inf` is `count`
+    buckets: [u64; 11],
+    sum: f64,
+    count: u64,
+}
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[i] += 1;
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+/// How long `Metrics::storage_stats` reuses a count of stored pastes,
+/// summing all paste content on every scrape is a full table scan
+const STORAGE_STATS_TTL: time::Duration = time::Duration::from_secs(60);
+
+/// A metric value owned elsewhere and gathered at scrape time
+pub struct Sample {
+    pub name: &'static str,
+    pub help: &'static str,
+    // prometheus metric type, `counter` or `gauge`
+    pub kind: &'static str,
+    pub value: f64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    // (route, status) -> latency histogram
+    requests: Mutex<BTreeMap<(&'static str, u16), Histogram>>,
+    // (when counted, (paste count, content bytes))
+    storage: Mutex<Option<(time::Instant, (i64, i64))>>,
+
+    pub pastes_created: AtomicU64,
+    pub pastes_viewed: AtomicU64,
+    pub pastes_expired: AtomicU64,
+    pub sweeper_runs: AtomicU64,
+    pub sweeper_deleted: AtomicU64,
+    pub sweeper_errors: AtomicU64,
+    pub decryption_failures: AtomicU64,
+    pub decryption_lockouts: AtomicU64,
+    pub key_misses: AtomicU64,
+    pub key_miss_throttles: AtomicU64,
+}
+
+/// Increment a counter by one
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a completed request for the given route
+    pub fn observe_request(&self, route: &'static str, status: u16, elapsed: time::Duration) {
+        let secs = elapsed.as_secs_f64();
+        match self.requests.lock() {
+            Ok(mut requests) => requests.entry((route, status)).or_default().observe(secs),
+            Err(e) => error!("metrics lock poisoned: {}", e),
+        }
+    }
+
+    /// Number of stored pastes and their total bytes, recounted with
+    /// `count` at most once per `STORAGE_STATS_TTL`
+    pub fn storage_stats<F>(&self, count: F) -> Result<(i64, i64)>
+    where
+        F: FnOnce() -> Result<(i64, i64)>,
+    {
+        let mut storage = self
+            .storage
+            .lock()
+            .map_err(|e| format_err!(ErrorKind::SyncPoison, "metrics lock poisoned: {}", e))?;
+        match *storage {
+            Some((counted, stats)) if counted.elapsed() < STORAGE_STATS_TTL => Ok(stats),
+            _ => {
+                let stats = count()?;
+                *storage = Some((time::Instant::now(), stats));
+                Ok(stats)
+            }
+        }
+    }
+
+    /// Render all metrics, plus any scrape-time `samples`
+    pub fn render(&self, samples: &[Sample]) -> Result<String> {
+        let mut out = String::new();
+        let requests = self
+            .requests
+            .lock()
+            .map_err(|e| format_err!(ErrorKind::SyncPoison, "metrics lock poisoned: {}", e))?;
+
+        out.push_str("# HELP upaste_http_requests_total Total HTTP requests by route and status\n");
+        out.push_str("# TYPE upaste_http_requests_total counter\n");
+        for ((route, status), hist) in requests.iter() {
+            writeln!(
+                out,
+                "upaste_http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                route, status, hist.count
+            )
+            .ok();
+        }
+
+        out.push_str(
+            "# HELP upaste_http_request_duration_seconds HTTP request latency by route and status\n",
+        );
+        out.push_str("# TYPE upaste_http_request_duration_seconds histogram\n");
+        for ((route, status), hist) in requests.iter() {
+            let labels = format!("route=\"{}\",status=\"{}\"", route, status);
+            let mut cumulative = 0;
+            for (le, n) in LATENCY_BUCKETS.iter().zip(hist.buckets.iter()) {
+                cumulative += n;
+                writeln!(
+                    out,
+                    "upaste_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, le, cumulative
+                )
+                .ok();
+            }
+            writeln!(
+                out,
+                "upaste_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, hist.count
+            )
+            .ok();
+            writeln!(
+                out,
+                "upaste_http_request_duration_seconds_sum{{{}}} {}",
+                labels, hist.sum
+            )
+            .ok();
+            writeln!(
+                out,
+                "upaste_http_request_duration_seconds_count{{{}}} {}",
+                labels, hist.count
+            )
+            .ok();
+        }
+
+        let counters = [
+            (
+                "upaste_pastes_created_total",
+                "Pastes created",
+                &self.pastes_created,
+            ),
+            (
+                "upaste_pastes_viewed_total",
+                "Pastes viewed",
+                &self.pastes_viewed,
+            ),
+            (
+                "upaste_pastes_expired_total",
+                "Pastes removed after expiring or going stale",
+                &self.pastes_expired,
+            ),
+            (
+                "upaste_sweeper_runs_total",
+                "Stale paste sweeper runs",
+                &self.sweeper_runs,
+            ),
+            (
+                "upaste_sweeper_deleted_total",
+                "Pastes deleted by the stale paste sweeper",
+                &self.sweeper_deleted,
+            ),
+            (
+                "upaste_sweeper_errors_total",
+                "Failed stale paste sweeper runs",
+                &self.sweeper_errors,
+            ),
+            (
+                "upaste_decryption_failures_total",
+                "Failed paste decryptions or signature checks",
+                &self.decryption_failures,
+            ),
+            (
+                "upaste_decryption_lockouts_total",
+                "Decryption attempts refused while backing off",
+                &self.decryption_lockouts,
+            ),
+            (
+                "upaste_key_misses_total",
+                "Lookups of pastes that don't exist",
+                &self.key_misses,
+            ),
+            (
+                "upaste_key_miss_throttles_total",
+                "Paste lookups refused while a client backs off after key misses",
+                &self.key_miss_throttles,
+            ),
+        ];
+        for (name, help, counter) in counters.iter() {
+            writeln!(out, "# HELP {} {}", name, help).ok();
+            writeln!(out, "# TYPE {} counter", name).ok();
+            writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed)).ok();
+        }
+
+        for sample in samples {
+            writeln!(out, "# HELP {} {}", sample.name, sample.help).ok();
+            writeln!(out, "# TYPE {} {}", sample.name, sample.kind).ok();
+            writeln!(out, "{} {}", sample.name, sample.value).ok();
+        }
+        Ok(out)
+    }
+}
@@ -1,10 +1,14 @@
-use chrono::{DateTime, Duration, TimeZone, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use rand::{self, Rng};
-use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
-use rusqlite::{self, Connection};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, Value, ValueRef};
+use rusqlite::{self, Connection, OptionalExtension, TransactionBehavior};
+use std::collections::HashMap;
 use std::ops;
 
+use crate::crypto::{Enc, Envelope, WrappedKey};
 use crate::errors::*;
+use crate::service::DbPool;
+use crate::workers::WorkerPool;
 
 /// Generate a new random key
 fn gen_key(n_chars: usize) -> String {
@@ -19,6 +23,11 @@ fn gen_key(n_chars: usize) -> String {
         .collect::<String>()
 }
 
+/// Key for a private paste, 128 random bits so it can't be enumerated
+fn gen_private_key() -> Result<String> {
+    Ok(hex::encode(crate::crypto::rand_bytes(16)?))
+}
+
 /// Create a new paste.key, making sure it isn't already in use
 fn get_new_key(conn: &Connection) -> Result<String> {
     let mut n_chars = 5;
@@ -50,76 +59,268 @@ impl ops::DerefMut for Dt {
 }
 
 impl FromSql for Dt {
-    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
         value
             .as_i64()
             .map(|timestamp| Dt(Utc.timestamp(timestamp, 0)))
     }
 }
 impl ToSql for Dt {
-    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
         Ok(self.timestamp().into())
     }
 }
 
+impl FromSql for Envelope {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let s = value.as_str()?;
+        serde_json::from_str(s).map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+impl ToSql for Envelope {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let s = serde_json::to_string(self)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        Ok(s.into())
+    }
+}
+
+/// Paste content as stored: text for rows that were never encrypted,
+/// a blob of ciphertext otherwise
+struct Stored(Vec<u8>);
+impl FromSql for Stored {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Text(b) | ValueRef::Blob(b) => Ok(Stored(b.to_vec())),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
 pub struct NewPaste {
     pub content: String,
     pub content_type: String,
+    // `content` is ciphertext from the client, it's stored as is and never signed
+    pub client_encrypted: bool,
+    // delete the paste after this many failed decryptions
+    pub max_failed_attempts: Option<u32>,
+    // encrypt to these recipient names or hex X25519 public keys, see `Recipient`
+    pub recipients: Vec<String>,
+    // gets a long unguessable key, which is all it takes to read it,
+    // and must be left out of anything that lists or searches pastes
+    pub private: bool,
 }
 
 impl NewPaste {
     pub fn insert(
         self,
-        conn: &mut Connection,
+        db: &DbPool,
         config: &crate::Config,
         ttl_seconds: Option<u32>,
         encryption_key: Option<&str>,
+        kdf_pool: &WorkerPool,
     ) -> Result<Paste> {
-        let trans = conn.transaction()?;
-        let key = get_new_key(&trans)?;
-        let sig = crate::crypto::hmac_sign_with_key(&self.content, &config.signing_key);
-        let (nonce, salt, content) = if let Some(enc_key) = encryption_key {
-            let enc = crate::crypto::encrypt_with_key(&self.content, enc_key)?;
-            (Some(enc.nonce), Some(enc.salt), enc.value)
+        if self.client_encrypted && encryption_key.is_some() {
+            bail_fmt!(
+                ErrorKind::BadRequest,
+                "client encrypted pastes can't also use an encryption key"
+            );
+        }
+        // the connection isn't held while the key is stretched on `kdf_pool`,
+        // which can take a while when it's busy
+        let conn = db.get()?;
+        let recipients = self
+            .recipients
+            .iter()
+            .map(|r| Recipient::resolve(&conn, r))
+            .collect::<Result<Vec<_>>>()?;
+        if !recipients.is_empty() && (self.client_encrypted || encryption_key.is_some()) {
+            bail_fmt!(
+                ErrorKind::BadRequest,
+                "recipient encrypted pastes can't also use an encryption key or client encryption"
+            );
+        }
+        match ttl_seconds {
+            Some(0) => bail_fmt!(ErrorKind::BadRequest, "ttl must be positive"),
+            Some(ttl) if ttl > config.max_ttl_seconds => bail_fmt!(
+                ErrorKind::BadRequest,
+                "ttl can't be more than {} seconds",
+                config.max_ttl_seconds
+            ),
+            _ => (),
+        }
+        match self.max_failed_attempts {
+            Some(0) => bail_fmt!(
+                ErrorKind::BadRequest,
+                "max_failed_attempts must be a positive integer"
+            ),
+            // failures can only be counted when the server decrypts
+            Some(_) if encryption_key.is_none() && recipients.is_empty() => bail_fmt!(
+                ErrorKind::BadRequest,
+                "max_failed_attempts requires an encryption key or recipients"
+            ),
+            _ => (),
+        }
+        // encryption happens outside of any transaction, a deferred one can't
+        // upgrade to a write lock if another paste was inserted in the meantime
+        let key = if self.private {
+            gen_private_key()?
         } else {
-            (None, None, self.content)
+            get_new_key(&conn)?
         };
-
-        let stmt = "insert into pastes (key, content, content_type, date_created, date_viewed, exp_date, nonce, salt, signature) values (?, ?, ?, ?, ?, ?, ?, ?, ?)";
+        drop(conn);
         let now = Dt::now();
-        let exp_date = ttl_seconds.map(|secs| {
-            Dt(now
-                .checked_add_signed(Duration::seconds(secs as i64))
-                .expect("invalid date operation"))
-        });
+        // there's no plaintext to sign for client encrypted pastes
+        let sig = if self.client_encrypted {
+            None
+        } else {
+            Some(crate::crypto::hmac_sign_with_key(
+                &self.content,
+                &config.signing_key,
+            ))
+        };
+        // attests to the content as served, ciphertext for client encrypted pastes
+        let ed25519_signature = Some(crate::crypto::ed25519_sign(
+            &crate::crypto::attestation(
+                &key,
+                &crate::crypto::sha256_hex(&self.content),
+                now.timestamp(),
+            ),
+            &config.signing_key,
+        )?);
+        let signing_key_id = Some(config.signing_key_id.clone());
+        // both layers are bound to the paste key so rows can't be swapped
+        let mut wrapped = vec![];
+        let (nonce, salt, envelope, body) = if !recipients.is_empty() {
+            let (enc, keys) =
+                crate::crypto::encrypt_for_recipients(&self.content, &recipients, key.as_bytes())?;
+            wrapped = keys;
+            (
+                Some(enc.nonce),
+                Some(enc.salt),
+                Some(enc.envelope),
+                enc.value,
+            )
+        } else if let Some(enc_key) = encryption_key {
+            let (content, enc_key, aad) = (self.content.clone(), enc_key.to_string(), key.clone());
+            let enc = kdf_pool.run(move || {
+                crate::crypto::encrypt_with_key(&content, &enc_key, aad.as_bytes())
+            })??;
+            (
+                Some(enc.nonce),
+                Some(enc.salt),
+                Some(enc.envelope),
+                enc.value,
+            )
+        } else {
+            (None, None, None, self.content.as_bytes().to_vec())
+        };
+        let rest = crate::crypto::encrypt_at_rest(&body, &config.encryption_key, key.as_bytes())?;
+        let rest_key_id = config.encryption_key_id.clone();
+
+        let recipient_encrypted = !wrapped.is_empty();
+        let stmt = "insert into pastes (key, content, content_type, date_created, date_viewed, exp_date, nonce, salt, signature, signing_key_id, rest_nonce, rest_salt, rest_key_id, client_encrypted, envelope, rest_envelope, max_failed_attempts, recipient_encrypted, ed25519_signature, private) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+        // whole seconds, as stored
+        let exp_date = ttl_seconds.map(|secs| Dt(Utc.timestamp(now.timestamp() + secs as i64, 0)));
+        let mut conn = db.get()?;
+        let trans = conn.transaction()?;
         let paste = try_insert_to_model!(
-                [trans, stmt, &[&key as &dyn ToSql, &content, &self.content_type, &now, &now, &exp_date, &nonce, &salt, &sig]] ;
+                [trans, stmt, &[&key as &dyn ToSql, &rest.value, &self.content_type, &now, &now, &exp_date, &nonce, &salt, &sig, &signing_key_id, &rest.nonce, &rest.salt, &rest_key_id, &self.client_encrypted, &envelope, &rest.envelope, &self.max_failed_attempts, &recipient_encrypted, &ed25519_signature, &self.private]] ;
                 Paste ;
                 date_created: now.clone(), date_viewed: now,
-                key: key, content: content, content_type: self.content_type, exp_date: exp_date,
-                nonce: nonce, salt: salt, signature: Some(sig));
+                key: key, content: self.content, body: rest.value, content_type: self.content_type, exp_date: exp_date,
+                nonce: nonce, salt: salt, signature: sig, signing_key_id: signing_key_id,
+                rest_nonce: Some(rest.nonce), rest_salt: Some(rest.salt), rest_key_id: Some(rest_key_id),
+                client_encrypted: self.client_encrypted,
+                envelope: envelope, rest_envelope: Some(rest.envelope),
+                failed_attempts: 0, last_failed_attempt: None,
+                max_failed_attempts: self.max_failed_attempts,
+                recipient_encrypted: recipient_encrypted,
+                ed25519_signature: ed25519_signature,
+                share_generation: 0, private: self.private, hex_payload: false);
+        {
+            let mut insert = trans.prepare(
+                "insert into paste_recipients (paste_id, public_key, ephemeral_public_key, nonce, wrapped_key) values (?, ?, ?, ?, ?)",
+            )?;
+            for w in &wrapped {
+                insert.execute(&[
+                    &paste.id as &dyn ToSql,
+                    &w.public_key,
+                    &w.ephemeral_public_key,
+                    &w.nonce,
+                    &w.value,
+                ])?;
+            }
+        }
         trans.commit()?;
         Ok(paste)
     }
 }
 
-#[derive(Debug)]
+/// `DoesNotExist` message for pastes removed on read after expiring
+pub static PASTE_EXPIRED: &str = "paste expired";
+
+/// Pastes past their explicit expiration (`$1`), or not viewed since the
+/// max-age cutoff (`$2`) and without one, or with one further out than the
+/// max ttl (`$3`) allows, as older versions didn't cap it.
+/// sqlite numbers `$n` parameters by first use, so they must stay in order.
+static OUTDATED_FILTER: &str = "(exp_date is not null and exp_date < $1) \
+     or (date_viewed < $2 and (exp_date is null or exp_date > date_created + $3))";
+
+#[derive(Debug, Clone)]
 pub struct Paste {
     pub id: i64,
     pub key: String,
+    // plaintext, or ciphertext from the client, once opened by `get`
     pub content: String,
+    // `content` as stored, emptied once opened by `get`
+    body: Vec<u8>,
     pub content_type: String,
     pub date_created: Dt,
     pub date_viewed: Dt,
     pub exp_date: Option<Dt>,
-    pub nonce: Option<String>,
-    pub salt: Option<String>,
-    pub signature: Option<String>,
+    pub nonce: Option<Vec<u8>>,
+    pub salt: Option<Vec<u8>>,
+    pub signature: Option<Vec<u8>>,
+    // `None` for pastes signed before key ids were recorded,
+    // also identifies the key behind `ed25519_signature`
+    pub signing_key_id: Option<String>,
+    // `None` for pastes stored before encryption at rest
+    pub rest_nonce: Option<Vec<u8>>,
+    pub rest_salt: Option<Vec<u8>>,
+    pub rest_key_id: Option<String>,
+    pub client_encrypted: bool,
+    // `None` for unencrypted or legacy rows, see `Envelope::legacy`
+    pub envelope: Option<Envelope>,
+    pub rest_envelope: Option<Envelope>,
+    // consecutive failed decryptions, see `lockout::Backoff`
+    pub failed_attempts: u32,
+    pub last_failed_attempt: Option<Dt>,
+    pub max_failed_attempts: Option<u32>,
+    // the user layer is under a data key wrapped for recipients, see `Recipient`
+    pub recipient_encrypted: bool,
+    // see `crypto::attestation`, `None` for pastes created before these signatures
+    pub ed25519_signature: Option<Vec<u8>>,
+    // bumped to revoke share links, see `Paste::share_link`
+    pub share_generation: u32,
+    pub private: bool,
+    // the user ciphertext under the at-rest layer is hex encoded,
+    // for rows written by older versions, see `unhex_payloads`
+    pub hex_payload: bool,
+}
+
+/// Outcome of `Paste::resign_all`
+#[derive(Debug, Default)]
+pub struct ResignStats {
+    pub resigned: usize,
+    // encrypted pastes can't be re-signed without their user key
+    pub skipped_encrypted: usize,
+    pub invalid: usize,
 }
 impl Paste {
     #[inline]
     fn all_rows() -> &'static str {
-        "id, key, content, content_type, date_created, date_viewed, exp_date, nonce, salt, signature"
+        "id, key, content, content_type, date_created, date_viewed, exp_date, nonce, salt, signature, signing_key_id, rest_nonce, rest_salt, rest_key_id, client_encrypted, envelope, rest_envelope, failed_attempts, last_failed_attempt, max_failed_attempts, recipient_encrypted, ed25519_signature, share_generation, private, hex_payload"
     }
 
     pub fn table_name() -> &'static str {
@@ -130,7 +331,8 @@ impl Paste {
         Ok(Self {
             id: row.get(0).expect("row id error"),
             key: row.get(1).expect("row key error"),
-            content: row.get(2).expect("row content error"),
+            content: String::new(),
+            body: row.get::<_, Stored>(2).expect("row content error").0,
             content_type: row.get(3).expect("row content_type error"),
             date_created: row.get(4).expect("row date_created error"),
             date_viewed: row.get(5).expect("row date_viewed error"),
@@ -138,44 +340,544 @@ impl Paste {
             nonce: row.get(7).expect("row nonce error"),
             salt: row.get(8).expect("row salt error"),
             signature: row.get(9).expect("row signature error"),
+            signing_key_id: row.get(10).expect("row signing_key_id error"),
+            rest_nonce: row.get(11).expect("row rest_nonce error"),
+            rest_salt: row.get(12).expect("row rest_salt error"),
+            rest_key_id: row.get(13).expect("row rest_key_id error"),
+            client_encrypted: row.get(14).expect("row client_encrypted error"),
+            envelope: row.get(15).expect("row envelope error"),
+            rest_envelope: row.get(16).expect("row rest_envelope error"),
+            failed_attempts: row.get(17).expect("row failed_attempts error"),
+            last_failed_attempt: row.get(18).expect("row last_failed_attempt error"),
+            max_failed_attempts: row.get(19).expect("row max_failed_attempts error"),
+            recipient_encrypted: row.get(20).expect("row recipient_encrypted error"),
+            ed25519_signature: row.get(21).expect("row ed25519_signature error"),
+            share_generation: row.get(22).expect("row share_generation error"),
+            private: row.get(23).expect("row private error"),
+            hex_payload: row.get(24).expect("row hex_payload error"),
         })
     }
 
+    /// Entity tag identifying this paste's content, a hash of its
+    /// signature or of the content for legacy unsigned rows. The
+    /// signature itself is an HMAC under `SIGNING_KEY` and must not leak.
+    pub fn etag(&self) -> String {
+        let tag = match self.signature {
+            Some(ref sig) => crate::crypto::sha256_hex(&hex::encode(sig)),
+            None => crate::crypto::sha256_hex(&self.content),
+        };
+        format!("\"{}\"", tag)
+    }
+
+    /// Remove the at-rest encryption layer from `body`,
+    /// leaving it as plaintext or user ciphertext
+    fn open_at_rest(&mut self, config: &crate::Config) -> Result<()> {
+        let key_id = match self.rest_key_id {
+            Some(ref id) => id,
+            None => return Ok(()),
+        };
+        let master_key = config
+            .encryption_key(key_id)
+            .ok_or_else(|| format!("unknown encryption key id {:?}", key_id))?;
+        let envelope = self
+            .rest_envelope
+            .clone()
+            .unwrap_or_else(Envelope::legacy_at_rest);
+        let enc = Enc {
+            value: std::mem::take(&mut self.body),
+            nonce: self.rest_nonce.clone().unwrap_or_default(),
+            salt: self.rest_salt.clone().unwrap_or_default(),
+            envelope,
+        };
+        self.body = crate::crypto::decrypt_at_rest(&enc, master_key, self.key.as_bytes())
+            .chain_err(|| format!("at-rest decryption failure for paste id {}", self.id))?;
+        if self.hex_payload {
+            self.body = hex::decode(&self.body)
+                .chain_err(|| format!("invalid hex ciphertext for paste id {}", self.id))?;
+        }
+        Ok(())
+    }
+
+    /// Take the opened `body` as utf8 `content`
+    fn take_content(&mut self) -> Result<()> {
+        let body = std::mem::take(&mut self.body);
+        self.content = String::from_utf8(body)
+            .chain_err(|| format!("invalid utf8 content for paste id {}", self.id))?;
+        Ok(())
+    }
+
+    /// Encrypt pastes stored in plaintext, and re-encrypt pastes under
+    /// retired master keys or legacy envelopes, with the current `encryption_key`
+    pub fn reencrypt_all(conn: &mut Connection, config: &crate::Config) -> Result<usize> {
+        Self::reseal_at_rest(
+            conn,
+            config,
+            "rest_key_id is null or rest_key_id != ? \
+             or rest_envelope is null or rest_envelope != ? or hex_payload",
+            &[
+                &config.encryption_key_id as &dyn ToSql,
+                &Envelope::current_at_rest(),
+            ],
+        )
+    }
+
+    /// Whether any at-rest layers still wrap hex user ciphertext
+    pub fn has_hex_payloads(conn: &Connection) -> Result<bool> {
+        Ok(conn.query_row(
+            "select exists(select 1 from pastes where hex_payload)",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Re-encrypt at-rest layers wrapping hex user ciphertext, written by
+    /// older versions, so they wrap the raw bytes instead. Runs after
+    /// `unhex_all` since it needs the master keys.
+    pub fn unhex_payloads(conn: &mut Connection, config: &crate::Config) -> Result<usize> {
+        Self::reseal_at_rest(conn, config, "hex_payload", &[])
+    }
+
+    /// Replace the at-rest layer of pastes matching `filter` with one under
+    /// the current `encryption_key` and envelope, wrapping raw user ciphertext
+    fn reseal_at_rest(
+        conn: &mut Connection,
+        config: &crate::Config,
+        filter: &str,
+        params: &[&dyn ToSql],
+    ) -> Result<usize> {
+        let trans = conn.transaction()?;
+        let mut count = 0;
+        {
+            let stmt = format!("select {} from pastes where {}", Paste::all_rows(), filter);
+            let mut select = trans.prepare(&stmt)?;
+            let mut update = trans.prepare(
+                "update pastes set content = ?, rest_nonce = ?, rest_salt = ?, rest_key_id = ?, rest_envelope = ?, hex_payload = 0 where id = ?",
+            )?;
+            let rows = select.query_map(params, Self::from_row)?;
+            for row in rows {
+                let mut paste = row?;
+                paste.open_at_rest(config)?;
+                let rest = crate::crypto::encrypt_at_rest(
+                    &paste.body,
+                    &config.encryption_key,
+                    paste.key.as_bytes(),
+                )?;
+                update.execute(&[
+                    &rest.value as &dyn ToSql,
+                    &rest.nonce,
+                    &rest.salt,
+                    &config.encryption_key_id,
+                    &rest.envelope,
+                    &paste.id,
+                ])?;
+                count += 1;
+            }
+        }
+        trans.commit()?;
+        Ok(count)
+    }
+
+    /// Convert ciphertext, nonces, salts and signatures stored as hex text
+    /// before they were blobs. SQLite can't decode hex itself so this runs
+    /// after migrations, and only touches rows that still have text columns.
+    ///
+    /// Older at-rest layers also wrap hex user ciphertext, see `unhex_payloads`.
+    pub fn unhex_all(conn: &mut Connection) -> Result<usize> {
+        fn unhex(value: Value) -> Result<Value> {
+            Ok(match value {
+                Value::Text(s) => Value::Blob(hex::decode(&s).chain_err(|| "invalid hex column")?),
+                v => v,
+            })
+        }
+
+        let trans = conn.transaction()?;
+        let mut count = 0;
+        {
+            // plaintext content of rows that were never encrypted stays text
+            let mut select = trans.prepare(
+                "select id, (rest_key_id is not null or nonce is not null), \
+                 content, nonce, salt, signature, rest_nonce, rest_salt from pastes \
+                 where typeof(nonce) = 'text' or typeof(salt) = 'text' \
+                 or typeof(signature) = 'text' or typeof(rest_nonce) = 'text' \
+                 or typeof(rest_salt) = 'text' or (typeof(content) = 'text' \
+                 and (rest_key_id is not null or nonce is not null))",
+            )?;
+            let mut update = trans.prepare(
+                "update pastes set content = ?, nonce = ?, salt = ?, signature = ?, rest_nonce = ?, rest_salt = ? where id = ?",
+            )?;
+            let rows = select.query_map(rusqlite::NO_PARAMS, |row| {
+                let columns = (2..8)
+                    .map(|i| row.get::<_, Value>(i))
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok((row.get::<_, i64>(0)?, row.get::<_, bool>(1)?, columns))
+            })?;
+            for row in rows {
+                let (id, encrypted, mut columns) = row?;
+                for (i, column) in columns.iter_mut().enumerate() {
+                    if i == 0 && !encrypted {
+                        continue;
+                    }
+                    let value = std::mem::replace(column, Value::Null);
+                    *column = unhex(value).chain_err(|| format!("paste id {}", id))?;
+                }
+                let mut params = columns.iter().map(|v| v as &dyn ToSql).collect::<Vec<_>>();
+                params.push(&id);
+                update.execute(&params)?;
+                count += 1;
+            }
+        }
+        trans.commit()?;
+        Ok(count)
+    }
+
+    /// Count a decryption attempt before the key is checked, refusing it
+    /// until the backoff from earlier attempts has passed. Counting up front
+    /// in one write transaction stops parallel guesses from all slipping
+    /// through the same backoff window or past `max_failed_attempts`.
+    /// Attempts that succeed reset the count, see `get`.
+    fn begin_attempt(&self, conn: &Connection, config: &crate::Config) -> Result<u32> {
+        let trans = rusqlite::Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+        let (attempts, last): (u32, Option<Dt>) = trans
+            .query_row(
+                "select failed_attempts, last_failed_attempt from pastes where id = ?",
+                &[&self.id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?
+            .ok_or_else(|| format_err!(ErrorKind::DoesNotExist, "paste not found"))?;
+        if let Some(last) = last {
+            let elapsed = Utc::now()
+                .signed_duration_since(*last)
+                .to_std()
+                .unwrap_or_default();
+            crate::lockout::Backoff::decryption(config).check(attempts, elapsed)?;
+        }
+        if let Some(max) = self.max_failed_attempts {
+            // attempts still in flight will use up the rest
+            if attempts >= max {
+                bail!(ErrorKind::TooManyAttempts(
+                    "too many failed decryption attempts".into(),
+                    1
+                ));
+            }
+        }
+        trans.execute(
+            "update pastes set failed_attempts = ?, last_failed_attempt = ? where id = ?",
+            &[&(attempts + 1) as &dyn ToSql, &Dt::now(), &self.id],
+        )?;
+        trans.commit()?;
+        Ok(attempts + 1)
+    }
+
+    /// Give back an attempt that failed for some reason other than the key
+    fn cancel_attempt(&self, conn: &Connection) -> Result<()> {
+        conn.execute(
+            "update pastes set failed_attempts = max(failed_attempts - 1, 0) where id = ?",
+            &[&self.id],
+        )?;
+        Ok(())
+    }
+
+    /// Record that an attempt counted by `begin_attempt` failed, deleting
+    /// the paste once it reaches its `max_failed_attempts`
+    fn record_failed_attempt(&self, conn: &Connection) -> Result<()> {
+        let trans = rusqlite::Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+        trans.execute(
+            "update pastes set last_failed_attempt = ? where id = ?",
+            &[&Dt::now() as &dyn ToSql, &self.id],
+        )?;
+        // parallel attempts may have counted more failures since `begin_attempt`
+        let attempts: Option<u32> = trans
+            .query_row(
+                "select failed_attempts from pastes where id = ? and failed_attempts >= max_failed_attempts",
+                &[&self.id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(attempts) = attempts {
+            trans.execute("delete from pastes where id = ?", &[&self.id])?;
+            warn!(
+                "paste id {} deleted after {} failed decryption attempts",
+                self.id, attempts
+            );
+        }
+        trans.commit()?;
+        Ok(())
+    }
+
+    /// Check `content` against the signature using the key it was signed with,
+    /// or any known key for signatures without a key id.
+    /// Unsigned legacy pastes are always valid.
+    fn verify_signature(
+        content: &str,
+        signature: Option<&[u8]>,
+        key_id: Option<&str>,
+        config: &crate::Config,
+    ) -> bool {
+        let sig = match signature {
+            Some(sig) => sig,
+            None => return true,
+        };
+        match key_id {
+            Some(id) => match config.signing_key(id) {
+                Some(key) => crate::crypto::hmac_verify_with_key(content, sig, key),
+                None => {
+                    error!("unknown signing key id {:?}", id);
+                    false
+                }
+            },
+            None => config
+                .signing_keys()
+                .any(|(_, key)| crate::crypto::hmac_verify_with_key(content, sig, key)),
+        }
+    }
+
+    /// What `ed25519_signature` covers, see `crypto::attestation`
+    pub fn attestation(&self) -> String {
+        crate::crypto::attestation(
+            &self.key,
+            &crate::crypto::sha256_hex(&self.content),
+            self.date_created.timestamp(),
+        )
+    }
+
+    fn verify_ed25519_signature(&self, config: &crate::Config) -> bool {
+        let sig = match self.ed25519_signature {
+            Some(ref sig) => sig,
+            None => return true,
+        };
+        self.signing_key_id
+            .as_deref()
+            .and_then(|id| config.signing_key(id))
+            .and_then(|key| crate::crypto::ed25519_public_key(key).ok())
+            .map(|public_key| crate::crypto::ed25519_verify(&self.attestation(), sig, &public_key))
+            .unwrap_or(false)
+    }
+
+    /// Re-sign unencrypted pastes that aren't signed with the current
+    /// signing key, skipping any whose existing signature doesn't verify.
+    /// Unencrypted pastes created before Ed25519 signatures are given one.
+    pub fn resign_all(conn: &mut Connection, config: &crate::Config) -> Result<ResignStats> {
+        let trans = conn.transaction()?;
+        let mut stats = ResignStats::default();
+        {
+            let stmt = format!(
+                "select {} from pastes where (signature is not null or ed25519_signature is not null) \
+                 and (signing_key_id is null or signing_key_id != ?) \
+                 or (ed25519_signature is null and nonce is null)",
+                Paste::all_rows()
+            );
+            let mut select = trans.prepare(&stmt)?;
+            let mut update = trans.prepare(
+                "update pastes set signature = ?, ed25519_signature = ?, signing_key_id = ? where id = ?",
+            )?;
+            let rows = select.query_map(&[&config.signing_key_id], Self::from_row)?;
+            for row in rows {
+                let mut paste = row?;
+                if paste.nonce.is_some() {
+                    stats.skipped_encrypted += 1;
+                    continue;
+                }
+                paste.open_at_rest(config)?;
+                paste.take_content()?;
+                if !Self::verify_signature(
+                    &paste.content,
+                    paste.signature.as_deref(),
+                    paste.signing_key_id.as_deref(),
+                    config,
+                ) || !paste.verify_ed25519_signature(config)
+                {
+                    error!(
+                        "paste id {} has an invalid signature, not re-signing",
+                        paste.id
+                    );
+                    stats.invalid += 1;
+                    continue;
+                }
+                // legacy unsigned and client encrypted pastes stay without an hmac
+                let sig = paste.signature.as_ref().map(|_| {
+                    crate::crypto::hmac_sign_with_key(&paste.content, &config.signing_key)
+                });
+                let ed25519_sig =
+                    crate::crypto::ed25519_sign(&paste.attestation(), &config.signing_key)?;
+                update.execute(&[
+                    &sig as &dyn ToSql,
+                    &ed25519_sig,
+                    &config.signing_key_id,
+                    &paste.id,
+                ])?;
+                stats.resigned += 1;
+            }
+        }
+        trans.commit()?;
+        Ok(stats)
+    }
+
     pub fn exists(conn: &Connection, key: &str) -> Result<bool> {
         let stmt = "select exists(select 1 from pastes where key = $1)";
         Ok(try_query_row!([conn, stmt, &[&key]], u8) == 1)
     }
 
-    pub fn count_outdated(conn: &Connection, date: &DateTime<Utc>) -> Result<i64> {
-        let stmt = format!(
-            "select count({}) from pastes where date_viewed < $1",
-            Paste::all_rows()
-        );
-        Ok(try_query_row!([conn, &stmt, &[&date.timestamp()]], i64))
+    /// Number of pastes `delete_outdated` would delete
+    pub fn count_outdated(
+        conn: &Connection,
+        max_cutoff: &DateTime<Utc>,
+        now: &DateTime<Utc>,
+        max_ttl_seconds: u32,
+    ) -> Result<i64> {
+        let stmt = format!("select count(*) from pastes where {}", OUTDATED_FILTER);
+        let params = [
+            now.timestamp(),
+            max_cutoff.timestamp(),
+            max_ttl_seconds as i64,
+        ];
+        Ok(try_query_row!([conn, &stmt, params], i64))
     }
 
     pub fn delete_outdated(
         conn: &Connection,
         max_cutoff: &DateTime<Utc>,
         now: &DateTime<Utc>,
+        max_ttl_seconds: u32,
     ) -> Result<i32> {
-        let stmt =
-            "delete from pastes where (exp_date is not null and exp_date < $1) or date_viewed < $2";
-        Ok(conn.execute(stmt, &[&now.timestamp(), &max_cutoff.timestamp()])? as i32)
+        let stmt = format!("delete from pastes where {}", OUTDATED_FILTER);
+        let params = [
+            now.timestamp(),
+            max_cutoff.timestamp(),
+            max_ttl_seconds as i64,
+        ];
+        Ok(conn.execute(&stmt, params)? as i32)
     }
 
-    pub fn touch_and_get(
+    /// Same as `delete_outdated`, but returns the keys of the deleted pastes
+    pub fn delete_outdated_keys(
         conn: &mut Connection,
-        key: &str,
-        enc_key: Option<&str>,
-        signing_key: &str,
-    ) -> Result<Self> {
-        let stmt_1 = "update pastes set date_viewed = ? where key = ?";
-        let stmt_2 = format!("select {} from pastes where key = ?", Paste::all_rows());
+        max_cutoff: &DateTime<Utc>,
+        now: &DateTime<Utc>,
+        max_ttl_seconds: u32,
+    ) -> Result<Vec<String>> {
+        let params = [
+            now.timestamp(),
+            max_cutoff.timestamp(),
+            max_ttl_seconds as i64,
+        ];
+        let trans = conn.transaction()?;
+        let keys = {
+            let stmt = format!("select key from pastes where {}", OUTDATED_FILTER);
+            let mut stmt = trans.prepare(&stmt)?;
+            let rows = stmt.query_map(params, |row| row.get::<_, String>(0))?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        let stmt = format!("delete from pastes where {}", OUTDATED_FILTER);
+        trans.execute(&stmt, params)?;
+        trans.commit()?;
+        Ok(keys)
+    }
+
+    /// Number of stored pastes and the total bytes of their content
+    pub fn storage_stats(conn: &Connection) -> Result<(i64, i64)> {
+        let stmt = "select count(*), coalesce(sum(length(cast(content as blob))), 0) from pastes";
+        Ok(conn.query_row(stmt, rusqlite::NO_PARAMS, |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?)
+    }
+
+    /// Bump the `date_viewed` of many pastes at once so they aren't
+    /// considered stale, never moving a `date_viewed` backwards
+    pub fn touch_all(conn: &mut Connection, views: &HashMap<String, Dt>) -> Result<()> {
+        let stmt = "update pastes set date_viewed = max(date_viewed, ?) where key = ?";
         let trans = conn.transaction()?;
-        trans.execute(stmt_1, &[&Dt::now() as &dyn ToSql, &key])?;
-        let mut paste = trans
-            .query_row(&stmt_2, &[&key], Self::from_row)
+        {
+            let mut stmt = trans.prepare(stmt)?;
+            for (key, date) in views {
+                stmt.execute(&[date as &dyn ToSql, key])?;
+            }
+        }
+        trans.commit()?;
+        Ok(())
+    }
+
+    /// Keys wrapped for the recipients of the paste with id `paste_id`
+    fn wrapped_keys(conn: &Connection, paste_id: i64) -> Result<Vec<WrappedKey>> {
+        let mut stmt = conn.prepare(
+            "select public_key, ephemeral_public_key, nonce, wrapped_key from paste_recipients where paste_id = ?",
+        )?;
+        let rows = stmt.query_map(&[&paste_id], |row| {
+            Ok(WrappedKey {
+                public_key: row.get(0)?,
+                ephemeral_public_key: row.get(1)?,
+                nonce: row.get(2)?,
+                value: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Public halves of the keys wrapped for a recipient encrypted paste, which
+    /// recipients need to derive their `Unlock::RecipientToken`
+    pub fn recipient_keys(conn: &Connection, key: &str) -> Result<Vec<WrappedKey>> {
+        let stmt = "select id from pastes where key = ? and recipient_encrypted";
+        match conn.query_row(stmt, &[&key], |row| row.get(0)) {
+            Ok(id) => Self::wrapped_keys(conn, id),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Decrypt the user layer `enc` with `unlock`. The outer error is for
+    /// failures unrelated to the credentials, the inner one is a failed decryption.
+    fn unlock(
+        &self,
+        enc: Enc,
+        unlock: &Unlock,
+        wrapped: &[WrappedKey],
+        kdf_pool: &WorkerPool,
+    ) -> Result<Result<String>> {
+        let aad = self.key.clone();
+        if !self.recipient_encrypted {
+            return match unlock {
+                Unlock::Key(enc_key) => {
+                    let enc_key = enc_key.clone();
+                    kdf_pool.run(move || {
+                        crate::crypto::decrypt_with_key(&enc, &enc_key, aad.as_bytes())
+                    })
+                }
+                _ => Ok(Err("recipient credentials for a key encrypted paste".into())),
+            };
+        }
+        let token = match unlock {
+            Unlock::Key(_) => {
+                return Ok(Err("encryption key for a recipient encrypted paste".into()))
+            }
+            Unlock::RecipientToken(token) => token.clone(),
+            Unlock::RecipientSecret(secret) => {
+                let public_key = match crate::crypto::x25519_public_key(secret) {
+                    Ok(public_key) => public_key,
+                    Err(e) => return Ok(Err(e)),
+                };
+                match wrapped.iter().find(|w| w.public_key == public_key) {
+                    Some(w) => match crate::crypto::recipient_token(w, secret) {
+                        Ok(token) => token.to_vec(),
+                        Err(e) => return Ok(Err(e)),
+                    },
+                    None => return Ok(Err("not a recipient of this paste".into())),
+                }
+            }
+        };
+        Ok(crate::crypto::decrypt_as_recipient(
+            &enc,
+            wrapped,
+            &token,
+            aad.as_bytes(),
+        ))
+    }
+
+    /// Fetch a paste as stored, without opening or decrypting it.
+    /// Expired pastes are deleted.
+    pub fn find(conn: &Connection, key: &str) -> Result<Self> {
+        let stmt = format!("select {} from pastes where key = ?", Paste::all_rows());
+        let paste = conn
+            .query_row(&stmt, &[&key], Self::from_row)
             .map_err(|e| match e {
                 rusqlite::Error::QueryReturnedNoRows => {
                     format_err!(ErrorKind::DoesNotExist, "paste not found")
@@ -184,33 +886,284 @@ impl Paste {
             })?;
         if let Some(ref exp_date) = paste.exp_date {
             if exp_date.0 <= Utc::now() {
-                trans.execute("delete from pastes where id = $1", &[&paste.id])?;
-                return Err(ErrorKind::DoesNotExist("paste expired".to_string()).into());
+                conn.execute("delete from pastes where id = $1", &[&paste.id])?;
+                return Err(ErrorKind::DoesNotExist(PASTE_EXPIRED.to_string()).into());
             }
         }
-        trans.commit()?;
-        if matches!(
-            (enc_key, paste.nonce.as_ref(), paste.salt.as_ref()),
-            (Some(_), Some(_), Some(_))
-        ) {
-            let enc = crate::crypto::Enc {
-                nonce: paste.nonce.clone().unwrap(),
-                salt: paste.salt.clone().unwrap(),
-                value: paste.content.clone(),
-            };
-            let dec = crate::crypto::decrypt_with_key(&enc, enc_key.unwrap())?;
-            paste.content = dec;
+        Ok(paste)
+    }
+
+    /// Mint a link that expires after `ttl_seconds`, an hour by default,
+    /// valid until `revoke_share_links`. Links can't outlive
+    /// `max_ttl_seconds` or the paste itself.
+    pub fn share_link(
+        &self,
+        ttl_seconds: Option<u32>,
+        config: &crate::Config,
+    ) -> Result<ShareLink> {
+        let now = Utc::now().timestamp();
+        let remaining = self.exp_date.as_ref().map(|exp| exp.timestamp() - now);
+        let ttl_seconds = match ttl_seconds {
+            Some(0) => bail_fmt!(
+                ErrorKind::BadRequest,
+                "ttl_seconds must be a positive integer"
+            ),
+            Some(ttl) if ttl > config.max_ttl_seconds => bail_fmt!(
+                ErrorKind::BadRequest,
+                "ttl_seconds can't be more than {} seconds",
+                config.max_ttl_seconds
+            ),
+            Some(ttl) => match remaining {
+                Some(remaining) if ttl as i64 > remaining => bail_fmt!(
+                    ErrorKind::BadRequest,
+                    "ttl_seconds can't be more than the {} seconds until the paste expires",
+                    remaining.max(0)
+                ),
+                _ => ttl as i64,
+            },
+            None => {
+                let ttl = (60 * 60).min(config.max_ttl_seconds as i64);
+                remaining.map_or(ttl, |remaining| ttl.min(remaining))
+            }
+        };
+        let expires = now + ttl_seconds;
+        let sig = crate::crypto::share_link_signature(
+            self.id,
+            &self.key,
+            expires,
+            self.share_generation,
+            &config.signing_key,
+        )?;
+        Ok(ShareLink {
+            paste_id: self.id,
+            expires,
+            sig,
+        })
+    }
+
+    /// Key of the paste `link` was minted for, if it's still valid
+    pub fn share_link_key(
+        conn: &Connection,
+        link: &ShareLink,
+        config: &crate::Config,
+    ) -> Result<String> {
+        let not_found = || format_err!(ErrorKind::DoesNotExist, "share link not found");
+        let (key, generation): (String, u32) = conn
+            .query_row(
+                "select key, share_generation from pastes where id = ?",
+                &[&link.paste_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => not_found(),
+                _ => ErrorKind::Sqlite(e),
+            })?;
+        let now = Utc::now().timestamp();
+        let valid = config.signing_keys().any(|(_, signing_key)| {
+            crate::crypto::share_link_verify(
+                link.paste_id,
+                &key,
+                link.expires,
+                generation,
+                &link.sig,
+                signing_key,
+                now,
+            )
+        });
+        if !valid {
+            return Err(not_found().into());
         }
-        if let Some(sig) = &paste.signature {
-            if !crate::crypto::hmac_verify_with_key(&paste.content, sig, signing_key) {
-                error!("decryption error, invalid signature");
-                bail_fmt!(ErrorKind::DecryptionError, "decryption failure")
+        Ok(key)
+    }
+
+    pub fn revoke_share_links(conn: &Connection, id: i64) -> Result<()> {
+        conn.execute(
+            "update pastes set share_generation = share_generation + 1 where id = ?",
+            &[&id],
+        )?;
+        Ok(())
+    }
+
+    /// Fetch a paste, decrypting it with `unlock` if it's encrypted.
+    /// Decryption attempts are counted before the key is checked and
+    /// back off exponentially, failing with `TooManyAttempts`, see `begin_attempt`.
+    ///
+    /// This does not update `date_viewed`, views should be recorded
+    /// separately, see `views::ViewTracker`.
+    pub fn get(
+        db: &DbPool,
+        key: &str,
+        unlock: Option<&Unlock>,
+        config: &crate::Config,
+        kdf_pool: &WorkerPool,
+    ) -> Result<Self> {
+        let conn = db.get()?;
+        let mut paste = Self::find(&conn, key)?;
+        paste.open_at_rest(config)?;
+        match (paste.nonce.clone(), paste.salt.clone()) {
+            (Some(nonce), Some(salt)) => {
+                let unlock = unlock.ok_or_else(|| {
+                    format_err!(ErrorKind::DecryptionError, "decryption key required")
+                })?;
+                let wrapped = if paste.recipient_encrypted {
+                    Self::wrapped_keys(&conn, paste.id)?
+                } else {
+                    vec![]
+                };
+                paste.failed_attempts = paste.begin_attempt(&conn, config)?;
+                // the connection isn't held while the key is stretched on
+                // `kdf_pool`, which can take a while when it's busy
+                drop(conn);
+                let enc = Enc {
+                    nonce,
+                    salt,
+                    value: std::mem::take(&mut paste.body),
+                    envelope: paste.envelope.clone().unwrap_or_else(Envelope::legacy),
+                };
+                let unlocked = paste.unlock(enc, unlock, &wrapped, kdf_pool);
+                let conn = db.get()?;
+                match unlocked {
+                    Ok(Ok(content)) => paste.content = content,
+                    Ok(Err(_)) => {
+                        paste.record_failed_attempt(&conn)?;
+                        bail_fmt!(ErrorKind::DecryptionError, "decryption failure")
+                    }
+                    Err(e) => {
+                        paste.cancel_attempt(&conn)?;
+                        return Err(e);
+                    }
+                }
+                conn.execute(
+                    "update pastes set failed_attempts = 0, last_failed_attempt = null where id = ?",
+                    &[&paste.id],
+                )?;
+                paste.failed_attempts = 0;
+                paste.last_failed_attempt = None;
             }
+            _ => paste.take_content()?,
+        }
+        if !Self::verify_signature(
+            &paste.content,
+            paste.signature.as_deref(),
+            paste.signing_key_id.as_deref(),
+            config,
+        ) {
+            error!("decryption error, invalid signature");
+            bail_fmt!(ErrorKind::DecryptionError, "decryption failure")
         }
         Ok(paste)
     }
 }
 
+/// A link to a paste that doesn't reveal its key, see `Paste::share_link`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShareLink {
+    pub paste_id: i64,
+    pub expires: i64,
+    pub sig: Vec<u8>,
+}
+impl ShareLink {
+    /// Parse a token from `to_token`
+    pub fn from_token(token: &str) -> Result<Self> {
+        let bad = || format_err!(ErrorKind::DoesNotExist, "share link not found");
+        let mut parts = token.splitn(3, '.');
+        let mut next = || parts.next().ok_or_else(bad);
+        let paste_id = next()?.parse().map_err(|_| bad())?;
+        let expires = next()?.parse().map_err(|_| bad())?;
+        let sig = hex::decode(next()?).map_err(|_| bad())?;
+        Ok(Self {
+            paste_id,
+            expires,
+            sig,
+        })
+    }
+
+    pub fn to_token(&self) -> String {
+        format!(
+            "{}.{}.{}",
+            self.paste_id,
+            self.expires,
+            hex::encode(&self.sig)
+        )
+    }
+}
+
+/// Credentials supplied to decrypt a paste
+pub enum Unlock {
+    // the key a paste was created with, see `x-upaste-encryption-key`
+    Key(String),
+    // a recipient's X25519 secret key
+    RecipientSecret(Vec<u8>),
+    // derived from a recipient's secret without sending it, see `crypto::recipient_token`
+    RecipientToken(Vec<u8>),
+}
+
+/// A named X25519 public key that pastes can be encrypted to
+#[derive(Debug, Clone)]
+pub struct Recipient {
+    pub id: i64,
+    pub name: String,
+    pub public_key: Vec<u8>,
+    pub date_created: Dt,
+}
+
+impl Recipient {
+    pub fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            public_key: row.get(2)?,
+            date_created: row.get(3)?,
+        })
+    }
+
+    pub fn create(conn: &Connection, name: &str, public_key: &[u8]) -> Result<Self> {
+        if public_key.len() != 32 {
+            bail_fmt!(
+                ErrorKind::BadRequest,
+                "recipient public keys must be 32 bytes (64 hex characters)"
+            );
+        }
+        let stmt = "insert into recipients (name, public_key, date_created) values (?, ?, ?)";
+        let now = Dt::now();
+        let recipient = try_insert_to_model!(
+                [conn, stmt, &[&name as &dyn ToSql, &public_key, &now]] ;
+                Recipient ;
+                name: name.to_string(), public_key: public_key.to_vec(), date_created: now);
+        Ok(recipient)
+    }
+
+    /// Remove a recipient, pastes already encrypted to them are unaffected
+    pub fn delete(conn: &Connection, name: &str) -> Result<bool> {
+        Ok(conn.execute("delete from recipients where name = ?", &[&name])? > 0)
+    }
+
+    pub fn all(conn: &Connection) -> Result<Vec<Self>> {
+        let mut stmt = conn
+            .prepare("select id, name, public_key, date_created from recipients order by name")?;
+        let rows = stmt.query_map(rusqlite::NO_PARAMS, Self::from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Public key of a recipient given by name, or directly as 64 hex characters
+    pub fn resolve(conn: &Connection, recipient: &str) -> Result<Vec<u8>> {
+        if recipient.len() == 64 {
+            if let Ok(public_key) = hex::decode(recipient) {
+                return Ok(public_key);
+            }
+        }
+        let stmt = "select public_key from recipients where name = ?";
+        Ok(conn
+            .query_row(stmt, &[&recipient], |row| row.get(0))
+            .map_err(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => {
+                    format_err!(ErrorKind::BadRequest, "unknown recipient {:?}", recipient)
+                }
+                _ => ErrorKind::Sqlite(e),
+            })?)
+    }
+}
+
 pub static CONTENT_TYPES: [&str; 147] = [
     "text",
     "abap",
@@ -360,3 +1313,315 @@ pub static CONTENT_TYPES: [&str; 147] = [
     "xquery",
     "yaml",
 ];
+
+#[cfg(test)]
+impl Paste {
+    /// An unencrypted paste that was never stored
+    pub(crate) fn unsaved(key: &str, content: &str) -> Self {
+        Self {
+            id: 0,
+            key: key.to_string(),
+            content: content.to_string(),
+            body: vec![],
+            content_type: "text".to_string(),
+            date_created: Dt::now(),
+            date_viewed: Dt::now(),
+            exp_date: None,
+            nonce: None,
+            salt: None,
+            signature: None,
+            signing_key_id: None,
+            rest_nonce: None,
+            rest_salt: None,
+            rest_key_id: None,
+            client_encrypted: false,
+            envelope: None,
+            rest_envelope: None,
+            failed_attempts: 0,
+            last_failed_attempt: None,
+            max_failed_attempts: None,
+            recipient_encrypted: false,
+            ed25519_signature: None,
+            share_generation: 0,
+            private: false,
+            hex_payload: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Migration directories, oldest first
+    fn migrations() -> Vec<std::path::PathBuf> {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/migrations");
+        let mut migrations = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_dir())
+            .collect::<Vec<_>>();
+        migrations.sort();
+        migrations
+    }
+
+    fn migrate(conn: &Connection, migration: &std::path::Path) {
+        let sql = std::fs::read_to_string(migration.join("up.sql")).unwrap();
+        conn.execute_batch(&sql).unwrap();
+    }
+
+    /// In-memory database with every migration before `tag` applied, on
+    /// a single connection so each checkout sees the same database
+    fn db_before(tag: &str) -> DbPool {
+        let manager = r2d2_sqlite::SqliteConnectionManager::memory();
+        let pool = r2d2::Pool::builder().max_size(1).build(manager).unwrap();
+        let conn = pool.get().unwrap();
+        for migration in migrations() {
+            if migration.file_name().unwrap().to_str().unwrap() >= tag {
+                break;
+            }
+            migrate(&conn, &migration);
+        }
+        drop(conn);
+        pool
+    }
+
+    /// In-memory database with every migration applied
+    fn db() -> DbPool {
+        // sorts after every timestamped tag
+        db_before("~")
+    }
+
+    fn config() -> crate::Config {
+        crate::Config::with_env(&[])
+    }
+
+    /// Encrypt `bytes` as `envelope` with a fresh nonce and salt,
+    /// returning the ciphertext, nonce and salt
+    fn seal(bytes: &[u8], pass: &str, envelope: &Envelope, aad: &[u8]) -> [Vec<u8>; 3] {
+        let nonce = crate::crypto::new_nonce().unwrap();
+        let salt = crate::crypto::new_salt().unwrap();
+        let value =
+            crate::crypto::encrypt_bytes(bytes, &nonce, pass.as_bytes(), &salt, envelope, aad)
+                .unwrap();
+        [value, nonce, salt]
+    }
+
+    #[test]
+    fn v0_pbkdf2_row_round_trip() {
+        let (db, config) = (db(), config());
+        let kdf_pool = WorkerPool::new("test-kdf", 1, 1).unwrap();
+        // as written before envelopes: pbkdf2 without associated data,
+        // under an at-rest layer wrapping the hex user ciphertext
+        let [value, nonce, salt] = seal(b"hello", "pass", &Envelope::legacy(), b"");
+        let [rest, rest_nonce, rest_salt] = seal(
+            hex::encode(value).as_bytes(),
+            &config.encryption_key,
+            &Envelope::legacy_at_rest(),
+            b"",
+        );
+        db.get()
+            .unwrap()
+            .execute(
+                "insert into pastes (key, content, nonce, salt, rest_nonce, rest_salt, rest_key_id, \
+                 hex_payload) values ('abc', ?, ?, ?, ?, ?, ?, 1)",
+                &[&rest as &dyn ToSql, &nonce, &salt, &rest_nonce, &rest_salt, &config.encryption_key_id],
+            )
+            .unwrap();
+
+        let unlock = Unlock::Key("pass".into());
+        let paste = Paste::get(&db, "abc", Some(&unlock), &config, &kdf_pool).unwrap();
+        assert_eq!(paste.content, "hello");
+
+        let unlock = Unlock::Key("wrong".into());
+        let err = Paste::get(&db, "abc", Some(&unlock), &config, &kdf_pool).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::DecryptionError(_)));
+    }
+
+    #[test]
+    fn hex_row_migrate() {
+        let (db, config) = (db(), config());
+        let kdf_pool = WorkerPool::new("test-kdf", 1, 1).unwrap();
+        let [value, nonce, salt] = seal(b"hello", "pass", &Envelope::legacy(), b"");
+        let [rest, rest_nonce, rest_salt] = seal(
+            hex::encode(value).as_bytes(),
+            &config.encryption_key,
+            &Envelope::current_at_rest(),
+            b"abc",
+        );
+        let sig = crate::crypto::hmac_sign_with_key("hello", &config.signing_key);
+        let hex_columns = [&rest, &nonce, &salt, &sig, &rest_nonce, &rest_salt].map(hex::encode);
+        let mut conn = db.get().unwrap();
+        conn.execute(
+            "insert into pastes (key, content, nonce, salt, signature, rest_nonce, rest_salt, \
+             rest_key_id, signing_key_id, rest_envelope, hex_payload) \
+             values ('abc', ?, ?, ?, ?, ?, ?, ?, ?, ?, 1)",
+            &[
+                &hex_columns[0] as &dyn ToSql,
+                &hex_columns[1],
+                &hex_columns[2],
+                &hex_columns[3],
+                &hex_columns[4],
+                &hex_columns[5],
+                &config.encryption_key_id,
+                &config.signing_key_id,
+                &Envelope::current_at_rest(),
+            ],
+        )
+        .unwrap();
+
+        // as `admin database migrate` does
+        assert_eq!(Paste::unhex_all(&mut conn).unwrap(), 1);
+        assert_eq!(Paste::unhex_all(&mut conn).unwrap(), 0);
+        assert!(Paste::has_hex_payloads(&conn).unwrap());
+        assert_eq!(Paste::unhex_payloads(&mut conn, &config).unwrap(), 1);
+        assert!(!Paste::has_hex_payloads(&conn).unwrap());
+        let (types, stored): (String, Vec<u8>) = conn
+            .query_row(
+                "select typeof(content) || typeof(nonce) || typeof(signature), content from pastes",
+                rusqlite::NO_PARAMS,
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(types, "blobblobblob");
+        // the user ciphertext is no longer hex encoded underneath
+        assert!(stored.len() < rest.len());
+        drop(conn);
+
+        let unlock = Unlock::Key("pass".into());
+        let paste = Paste::get(&db, "abc", Some(&unlock), &config, &kdf_pool).unwrap();
+        assert_eq!(paste.content, "hello");
+    }
+
+    #[test]
+    fn hex_payload_row_reencrypt_all() {
+        let (db, config) = (db(), config());
+        let kdf_pool = WorkerPool::new("test-kdf", 1, 1).unwrap();
+        let [value, nonce, salt] = seal(b"hello", "pass", &Envelope::legacy(), b"");
+        let [rest, rest_nonce, rest_salt] = seal(
+            hex::encode(value).as_bytes(),
+            &config.encryption_key,
+            &Envelope::current_at_rest(),
+            b"abc",
+        );
+        let mut conn = db.get().unwrap();
+        conn.execute(
+            "insert into pastes (key, content, nonce, salt, rest_nonce, rest_salt, rest_key_id, \
+             rest_envelope, hex_payload) values ('abc', ?, ?, ?, ?, ?, ?, ?, 1)",
+            &[
+                &rest as &dyn ToSql,
+                &nonce,
+                &salt,
+                &rest_nonce,
+                &rest_salt,
+                &config.encryption_key_id,
+                &Envelope::current_at_rest(),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(Paste::reencrypt_all(&mut conn, &config).unwrap(), 1);
+        assert_eq!(Paste::reencrypt_all(&mut conn, &config).unwrap(), 0);
+        let (stored, envelope): (Vec<u8>, Envelope) = conn
+            .query_row(
+                "select content, rest_envelope from pastes",
+                rusqlite::NO_PARAMS,
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(envelope, Envelope::current_at_rest());
+        // the user ciphertext is no longer hex encoded underneath
+        assert!(stored.len() < rest.len());
+        drop(conn);
+
+        let unlock = Unlock::Key("pass".into());
+        let paste = Paste::get(&db, "abc", Some(&unlock), &config, &kdf_pool).unwrap();
+        assert_eq!(paste.content, "hello");
+    }
+
+    #[test]
+    fn deleted_at_max_failed_attempts() {
+        let (db, mut config) = (db(), config());
+        // no backoff between the attempts
+        config.decrypt_free_attempts = 10;
+        let kdf_pool = WorkerPool::new("test-kdf", 1, 1).unwrap();
+        let new_paste = NewPaste {
+            content: "hello".into(),
+            content_type: "text".into(),
+            client_encrypted: false,
+            max_failed_attempts: Some(2),
+            recipients: vec![],
+            private: false,
+        };
+        let paste = new_paste
+            .insert(&db, &config, None, Some("pass"), &kdf_pool)
+            .unwrap();
+        let (right, wrong) = (Unlock::Key("pass".into()), Unlock::Key("wrong".into()));
+        let get = |unlock| Paste::get(&db, &paste.key, Some(unlock), &config, &kdf_pool);
+        let wrong_key = |result: Result<Paste>| matches!(result, Err(ref e) if matches!(e.kind(), ErrorKind::DecryptionError(_)));
+
+        assert!(wrong_key(get(&wrong)));
+        // a success resets the count
+        assert_eq!(get(&right).unwrap().content, "hello");
+        assert!(wrong_key(get(&wrong)));
+        assert!(wrong_key(get(&wrong)));
+        let deleted = get(&right);
+        assert!(matches!(deleted, Err(ref e) if matches!(e.kind(), ErrorKind::DoesNotExist(_))));
+    }
+
+    #[test]
+    fn hex_payload_migration() {
+        let db = db_before("20261018210000_hex-payload");
+        let conn = db.get().unwrap();
+        let v2 = r#"{"v":2,"alg":"aes-256-gcm","kdf":{"name":"hkdf-sha256"}}"#;
+        for (key, envelope, rest_envelope) in [
+            ("v0", None, None),
+            (
+                "v1",
+                None,
+                Some(r#"{"v":1,"alg":"aes-256-gcm","kdf":{"name":"hkdf-sha256"}}"#),
+            ),
+            ("v2", None, Some(v2)),
+            ("recipients", Some(v2), Some(v2)),
+        ] {
+            conn.execute(
+                "insert into pastes (key, content, nonce, rest_key_id, envelope, rest_envelope) \
+                 values (?, x'00', x'00', 'default', ?, ?)",
+                &[&key as &dyn ToSql, &envelope, &rest_envelope],
+            )
+            .unwrap();
+        }
+        conn.execute(
+            "insert into pastes (key, content, rest_key_id) values ('plain', x'00', 'default')",
+            rusqlite::NO_PARAMS,
+        )
+        .unwrap();
+        migrate(&conn, migrations().last().unwrap());
+
+        let rows = conn
+            .prepare("select key, hex_payload, envelope, rest_envelope from pastes order by id")
+            .unwrap()
+            .query_map(rusqlite::NO_PARAMS, |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, bool>(1)?,
+                    row.get::<_, Option<Envelope>>(2)?,
+                    row.get::<_, Option<Envelope>>(3)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        let current = Some(Envelope::current_at_rest());
+        assert_eq!(
+            rows,
+            [
+                ("v0".into(), true, None, None),
+                ("v1".into(), true, None, current.clone()),
+                ("v2".into(), false, None, current.clone()),
+                ("recipients".into(), false, current.clone(), current),
+                ("plain".into(), false, None, None),
+            ]
+        );
+    }
+}
@@ -5,22 +5,31 @@
 //!  - Mount static file handler
 //!
 use std::env;
-use std::io::Write;
+use std::panic;
 use std::path::Path;
 use std::sync;
+use std::sync::atomic::Ordering;
 use std::thread;
 use std::time;
 
-use chrono::{self, Local};
+use chrono::{self, DateTime, Utc};
 use migrant_lib::{Config, Settings};
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::Connection;
 use tera::Tera;
 
+use crate::cache::PasteCache;
 use crate::errors::*;
 use crate::handlers;
+use crate::health::SweeperStatus;
+use crate::lockout::{Backoff, ClientLockout};
+use crate::logging;
+use crate::metrics::{self, Metrics};
 use crate::models;
+use crate::shutdown::{self, Shutdown};
+use crate::views::ViewTracker;
+use crate::workers::WorkerPool;
 use crate::ToResponse;
 
 // convenience wrapper types
@@ -28,15 +37,44 @@ pub type DbPool = Pool<SqliteConnectionManager>;
 pub type State = sync::Arc<Resources>;
 
 /// Resources
-/// template and database access
+/// template, database and cache access
 pub struct Resources {
     pub tera: Tera,
     pub db: DbPool,
     pub config: crate::Config,
+    pub cache: PasteCache,
+    pub views: ViewTracker,
+    pub lockout: ClientLockout,
+    pub key_misses: ClientLockout,
+    pub kdf_pool: WorkerPool,
+    pub metrics: Metrics,
+    pub sweeper: sync::Mutex<SweeperStatus>,
+    pub started: DateTime<Utc>,
+    pub shutdown: Shutdown,
 }
 impl Resources {
-    pub fn new(tera: Tera, db: DbPool, config: crate::Config) -> Self {
-        Self { tera, db, config }
+    pub fn new(tera: Tera, db: DbPool, config: crate::Config) -> Result<Self> {
+        let cache = PasteCache::new(
+            config.paste_cache_size,
+            time::Duration::from_secs(config.paste_cache_ttl_seconds),
+        );
+        let lockout = ClientLockout::new(Backoff::decryption(&config));
+        let key_misses = ClientLockout::new(Backoff::key_misses(&config));
+        let kdf_pool = WorkerPool::new("kdf", config.kdf_workers, config.kdf_queue_size)?;
+        Ok(Self {
+            tera,
+            db,
+            config,
+            cache,
+            views: ViewTracker::new(),
+            lockout,
+            key_misses,
+            kdf_pool,
+            metrics: Metrics::new(),
+            sweeper: sync::Mutex::new(SweeperStatus::default()),
+            started: Utc::now(),
+            shutdown: Shutdown::new(),
+        })
     }
 }
 
@@ -56,87 +94,338 @@ pub fn establish_connection<T: AsRef<Path>>(database_path: T) -> Connection {
         .unwrap_or_else(|_| panic!("Error connection to {:?}.", database_path.as_ref()))
 }
 
-fn establish_connection_pool<T: AsRef<Path>>(database_path: T) -> DbPool {
+/// Applies the configured sqlite pragmas to each new pooled connection
+#[derive(Debug)]
+struct ConnectionCustomizer {
+    journal_mode: String,
+    synchronous: String,
+    busy_timeout: time::Duration,
+}
+impl r2d2::CustomizeConnection<Connection, rusqlite::Error> for ConnectionCustomizer {
+    fn on_acquire(&self, conn: &mut Connection) -> std::result::Result<(), rusqlite::Error> {
+        conn.busy_timeout(self.busy_timeout)?;
+        conn.pragma_update(None, "journal_mode", &self.journal_mode)?;
+        conn.pragma_update(None, "synchronous", &self.synchronous)?;
+        Ok(())
+    }
+}
+
+fn establish_connection_pool<T: AsRef<Path>>(
+    database_path: T,
+    config: &crate::Config,
+) -> Result<DbPool> {
     let manager = SqliteConnectionManager::file(database_path.as_ref());
-    Pool::new(manager).expect("Failed to create pool.")
+    let customizer = ConnectionCustomizer {
+        journal_mode: config.db_journal_mode.clone(),
+        synchronous: config.db_synchronous.clone(),
+        busy_timeout: time::Duration::from_millis(config.db_busy_timeout_ms),
+    };
+    let pool = Pool::builder()
+        .max_size(config.db_pool_size)
+        .connection_timeout(time::Duration::from_secs(
+            config.db_connection_timeout_seconds,
+        ))
+        .connection_customizer(Box::new(customizer))
+        .build(manager)?;
+    Ok(pool)
 }
 
-static ERROR_404: &str = r##"
+/// Service state over a freshly migrated database in `dir`
+#[cfg(test)]
+pub(crate) fn migrated_state(dir: &Path, config: crate::Config) -> State {
+    std::fs::create_dir_all(dir).unwrap();
+    let db_path = dir.join("upaste");
+    let settings = Settings::configure_sqlite()
+        .database_path(&db_path)
+        .unwrap()
+        .migration_location(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+        .unwrap()
+        .build()
+        .unwrap();
+    let migrant = Config::with_settings(&settings);
+    migrant.setup().unwrap();
+    migrant_lib::Migrator::with_config(&migrant.reload().unwrap())
+        .direction(migrant_lib::Direction::Up)
+        .all(true)
+        .show_output(false)
+        .apply()
+        .unwrap();
+    let db = establish_connection_pool(&db_path, &config).unwrap();
+    sync::Arc::new(Resources::new(Tera::default(), db, config).unwrap())
+}
+
+fn error_404(request_id: &str) -> String {
+    format!(
+        r##"
 <html>
     <pre>
         Nothing to see here... <img src="https://badge-cache.kominick.com/badge/~(=^.^)-meow-yellow.svg?style=social"/>
+
+        request id: {}
     </pre>
 </html>
-"##;
+"##,
+        request_id
+    )
+}
+
+/// JSON error response carrying the request's id
+fn json_error(msg: &str, status: u16, request_id: &str) -> rouille::Response {
+    json_error_with(msg, status, request_id, json!({}))
+}
+
+/// `json_error` with the fields of `extra` merged into the body
+fn json_error_with(
+    msg: &str,
+    status: u16,
+    request_id: &str,
+    extra: serde_json::Value,
+) -> rouille::Response {
+    let mut body = json!({ "error": msg, "request_id": request_id });
+    if let (Some(body), serde_json::Value::Object(extra)) = (body.as_object_mut(), extra) {
+        body.extend(extra);
+    }
+    body.to_resp().unwrap().with_status_code(status)
+}
+
+/// Convert a handler error into a response
+fn error_response(request: &rouille::Request, e: &Error, request_id: &str) -> rouille::Response {
+    use self::ErrorKind::*;
+    match e.kind() {
+        BadRequest(ref s) => json_error(s, 400, request_id),
+        // a missing or wrong key, pages ask for one instead, see `handlers::view_paste`
+        DecryptionError(ref s) => json_error(s, 400, request_id),
+        RecipientKeyRequired(Some(ref ephemeral_public_key)) => json_error_with(
+            "recipient key required",
+            400,
+            request_id,
+            json!({ "ephemeral_public_key": ephemeral_public_key }),
+        ),
+        RecipientKeyRequired(None) => json_error("recipient key required", 400, request_id),
+        // api clients get json, browsers get a page
+        DoesNotExist(_) if matches!(route_label(request), "/raw/{key}" | "/json/{key}") => {
+            json_error("paste not found", 404, request_id)
+        }
+        DoesNotExist(_) => rouille::Response::html(error_404(request_id)).with_status_code(404),
+        // payload too large / request entity to large
+        UploadTooLarge(ref s) => json_error(s, 413, request_id),
+        // service unavailable
+        OutOfSpace(ref s) => json_error(s, 503, request_id),
+        Overloaded(ref s) => json_error(s, 503, request_id).with_unique_header("Retry-After", "1"),
+        TooManyAttempts(ref s, retry_after) => json_error(s, 429, request_id)
+            .with_unique_header("Retry-After", retry_after.to_string()),
+        // timed out waiting on an exhausted connection pool
+        R2D2(_) => json_error("server busy, try again", 503, request_id)
+            .with_unique_header("Retry-After", "1"),
+        // busy timeout elapsed waiting on the database lock
+        Sqlite(rusqlite::Error::SqliteFailure(ref e, _))
+            if e.code == rusqlite::ErrorCode::DatabaseBusy =>
+        {
+            json_error("server busy, try again", 503, request_id)
+                .with_unique_header("Retry-After", "1")
+        }
+        _ => internal_error_response(request, request_id),
+    }
+}
+
+/// Generic 500, as html for browsers and json for everyone else
+fn internal_error_response(request: &rouille::Request, request_id: &str) -> rouille::Response {
+    let wants_html = request
+        .header("accept")
+        .is_some_and(|accept| accept.contains("text/html"));
+    if wants_html {
+        rouille::Response::html(format!(
+            "<html><pre>Something went wrong\n\nrequest id: {}</pre></html>",
+            request_id
+        ))
+        .with_status_code(500)
+    } else {
+        json_error("Something went wrong", 500, request_id)
+    }
+}
+
+/// Use the client's `X-Request-Id` if it looks sane, otherwise generate one
+fn request_id(request: &rouille::Request) -> String {
+    match request.header("x-request-id") {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= 64
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) =>
+        {
+            id.to_string()
+        }
+        _ => crate::crypto::rand_bytes(8)
+            .map(hex::encode)
+            .unwrap_or_default(),
+    }
+}
 
-fn init_db_sweeper(state: State) {
+/// Extract the message from a caught panic's payload
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "<unknown>"
+    }
+}
+
+/// How often stale pastes are swept
+pub const SWEEP_INTERVAL_SECONDS: u64 = 20;
+
+fn init_db_sweeper(state: State) -> thread::JoinHandle<()> {
     thread::spawn(move || loop {
-        let deleted: Result<i32> = (|| {
+        let deleted: Result<usize> = (|| {
             let cutoff = chrono::Utc::now()
                 .checked_sub_signed(chrono::Duration::seconds(
                     state.config.max_paste_age_seconds,
                 ))
                 .chain_err(|| "Error calculating stale cutoff date")?;
-            let conn = state.db.get()?;
-            models::Paste::delete_outdated(&conn, &cutoff, &chrono::Utc::now())
+            let mut conn = state.db.get()?;
+            // pending views need to land first, otherwise recently
+            // viewed pastes would look stale
+            state.views.flush(&mut conn)?;
+            let keys = models::Paste::delete_outdated_keys(
+                &mut conn,
+                &cutoff,
+                &chrono::Utc::now(),
+                state.config.max_ttl_seconds,
+            )?;
+            for key in &keys {
+                state.cache.remove(key)?;
+            }
+            state.lockout.prune()?;
+            state.key_misses.prune()?;
+            Ok(keys.len())
         })();
+        metrics::inc(&state.metrics.sweeper_runs);
+        let now = Utc::now();
+        if let Ok(mut status) = state.sweeper.lock() {
+            status.last_run = Some(now);
+            if let Err(ref e) = deleted {
+                status.last_error = Some((now, e.to_string()));
+            }
+        }
         match deleted {
             Ok(count) => {
+                let count_u64 = count as u64;
+                state
+                    .metrics
+                    .sweeper_deleted
+                    .fetch_add(count_u64, Ordering::Relaxed);
+                state
+                    .metrics
+                    .pastes_expired
+                    .fetch_add(count_u64, Ordering::Relaxed);
                 if count > 0 {
                     info!(" ** Cleaned out {} stale pastes **", count);
                 } else {
                     debug!(" ** Cleaned out {} stale pastes **", count);
                 }
             }
-            Err(e) => error!("Error cleaning stale pastes: {}", e),
+            Err(e) => {
+                metrics::inc(&state.metrics.sweeper_errors);
+                error!("Error cleaning stale pastes: {}", e)
+            }
         }
-        thread::sleep(time::Duration::from_secs(20));
-    });
-}
-
-pub fn start() -> Result<()> {
-    let config = crate::Config::load();
-    // Set a custom logging format & change the env-var to "LOG"
-    // e.g. LOG=info upaste serve
-    env_logger::Builder::new()
-        .format(|buf, record| {
-            writeln!(
-                buf,
-                "{} [{}] - [{}] -> {}",
-                Local::now().format("%Y-%m-%d_%H:%M:%S"),
-                record.level(),
-                record.module_path().unwrap_or("<unknown>"),
-                record.args()
-            )
-        })
-        .parse(&config.log_level)
-        .init();
+        if state
+            .shutdown
+            .sleep(time::Duration::from_secs(SWEEP_INTERVAL_SECONDS))
+        {
+            break;
+        }
+    })
+}
+
+/// Write any pending views, used periodically and on shutdown
+fn flush_views(state: &State) {
+    let flushed: Result<usize> = (|| {
+        let mut conn = state.db.get()?;
+        state.views.flush(&mut conn)
+    })();
+    match flushed {
+        Ok(count) => debug!(" ** Recorded views for {} pastes **", count),
+        Err(e) => error!("Error recording paste views: {}", e),
+    }
+}
+
+fn init_view_flusher(state: State) -> thread::JoinHandle<()> {
+    let interval = time::Duration::from_secs(state.config.view_flush_interval_seconds);
+    thread::spawn(move || {
+        while !state.shutdown.sleep(interval) {
+            flush_views(&state);
+        }
+    })
+}
+
+/// Fold the sqlite write-ahead log back into the main database file
+fn checkpoint(state: &State) -> Result<()> {
+    let conn = state.db.get()?;
+    conn.query_row(
+        "pragma wal_checkpoint(TRUNCATE)",
+        rusqlite::NO_PARAMS,
+        |_| Ok(()),
+    )?;
+    Ok(())
+}
+
+pub fn start(config: crate::Config) -> Result<()> {
+    // before any threads are started, so the signals are left to `wait_for_signal`
+    shutdown::block_signals();
+    crate::logging::init(&config);
+    for warning in config.check_keys()? {
+        warn!("{}, don't use this config in production", warning);
+    }
 
     // connect to our db
     let db = migrant_config()?
         .database_path()
         .chain_err(|| "Can't determine database path")?;
-    let db_pool = establish_connection_pool(&db);
+    let db_pool = establish_connection_pool(&db, &config)?;
     info!(" ** Established database connection pool **");
 
     // compile our template and initialize template engine
     let mut tera = compile_templates!("templates/**/*");
     tera.autoescape_on(vec!["html"]);
 
-    let state = sync::Arc::new(Resources::new(tera, db_pool, config.clone()));
-    init_db_sweeper(state.clone());
+    let state = sync::Arc::new(Resources::new(tera, db_pool, config.clone())?);
+    let sweeper = init_db_sweeper(state.clone());
+    let view_flusher = init_view_flusher(state.clone());
 
     let host = config.host();
-    info!(" ** Listening at {} **", &host);
-    rouille::start_server_with_pool(&host, None, move |request| {
-        let state = state.clone();
+    let server_state = state.clone();
+    let pool_size = thread::available_parallelism().map_or(8, |n| n.get() * 8);
+    let server = rouille::Server::new(&host, move |request| {
+        let state = server_state.clone();
+        let _in_flight = match state.shutdown.track_request() {
+            Some(in_flight) => in_flight,
+            None => {
+                return json_error("shutting down", 503, &request_id(request))
+                    .with_unique_header("Retry-After", "1")
+                    .with_unique_header("Connection", "close")
+            }
+        };
+        let route = route_label(request);
+
+        let paste_key = request_paste_key(request, route);
+        let request_id = request_id(request);
+        // tag every log line emitted while handling this request
+        let _log_scope = logging::RequestScope::enter(&request_id);
+        let (paste_key, request_id) = (paste_key.as_deref(), request_id.as_str());
 
-        let now = Local::now().format("%Y-%m-%d %H:%M%S");
         let log_ok = |req: &rouille::Request, resp: &rouille::Response, elap: time::Duration| {
-            let ms = (elap.as_secs() * 1_000) as f32 + (elap.subsec_nanos() as f32 / 1_000_000.);
+            state.metrics.observe_request(route, resp.status_code, elap);
+            let ms = elap.as_secs_f64() * 1_000.;
             info!(
-                "[{}] {} {} -> {} ({}ms)",
-                now,
+                method = req.method(),
+                path = req.raw_url(),
+                status = resp.status_code,
+                duration_ms = ms,
+                paste_key = paste_key;
+                "{} {} -> {} ({}ms)",
                 req.method(),
                 req.raw_url(),
                 resp.status_code,
@@ -144,44 +433,90 @@ pub fn start() -> Result<()> {
             )
         };
         let log_err = |req: &rouille::Request, elap: time::Duration| {
-            let ms = (elap.as_secs() * 1_000) as f32 + (elap.subsec_nanos() as f32 / 1_000_000.);
-            info!(
-                "[{}] Handler Panicked: {} {} ({}ms)",
-                now,
+            state.metrics.observe_request(route, 500, elap);
+            let ms = elap.as_secs_f64() * 1_000.;
+            error!(
+                method = req.method(),
+                path = req.raw_url(),
+                status = 500,
+                duration_ms = ms,
+                paste_key = paste_key;
+                "Handler Panicked: {} {} ({}ms)",
                 req.method(),
                 req.raw_url(),
                 ms
             )
         };
 
+        let handler_state = state.clone();
         rouille::log_custom(request, log_ok, log_err, move || {
-            match route_request(request, state) {
-                Ok(resp) => rouille::content_encoding::apply(request, resp),
-                Err(e) => {
-                    use self::ErrorKind::*;
-                    error!("Handler Error: {}", e);
-                    match e.kind() {
-                        BadRequest(ref s) => {
-                            let body = json!({ "error": s });
-                            body.to_resp().unwrap().with_status_code(400)
-                        }
-                        DoesNotExist(_) => rouille::Response::html(ERROR_404).with_status_code(404),
-                        UploadTooLarge(ref s) => {
-                            // payload too large / request entity to large
-                            let body = json!({ "error": s });
-                            body.to_resp().unwrap().with_status_code(413)
-                        }
-                        OutOfSpace(ref s) => {
-                            // service unavailable
-                            let body = json!({ "error": s });
-                            body.to_resp().unwrap().with_status_code(503)
-                        }
-                        _ => rouille::Response::text("Something went wrong").with_status_code(500),
-                    }
+            let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                route_request(request, handler_state)
+            }));
+            let resp = match result {
+                Ok(Ok(resp)) => rouille::content_encoding::apply(request, resp),
+                Ok(Err(e)) => {
+                    error!(
+                        method = request.method(),
+                        path = request.raw_url(),
+                        paste_key = paste_key;
+                        "Handler Error: {}",
+                        e
+                    );
+                    error_response(request, &e, request_id)
                 }
-            }
+                Err(payload) => {
+                    error!(
+                        method = request.method(),
+                        path = request.raw_url(),
+                        paste_key = paste_key;
+                        "Handler Panicked: {}",
+                        panic_message(&*payload)
+                    );
+                    internal_error_response(request, request_id)
+                }
+            };
+            resp.with_unique_header("X-Request-Id", request_id.to_string())
         })
-    });
+    })
+    .map_err(|e| format!("Failed to start server: {}", e))?
+    .pool_size(pool_size);
+    info!(" ** Listening at {} **", &host);
+    // accepts requests until the process exits
+    thread::Builder::new()
+        .name("http".into())
+        .spawn(move || server.run())
+        .chain_err(|| "Error starting server thread")?;
+
+    let signal = shutdown::wait_for_signal();
+    info!(" ** Received signal {}, shutting down **", signal);
+    stop(&state, vec![sweeper, view_flusher]);
+    info!(" ** Shutdown complete **");
+    Ok(())
+}
+
+/// Turn away new requests and wait on those in-flight, then stop the
+/// `background` threads and write out pending views and the write-ahead log
+fn stop(state: &State, background: Vec<thread::JoinHandle<()>>) {
+    state.shutdown.request();
+    let drained = state.shutdown.drain(time::Duration::from_secs(
+        state.config.shutdown_timeout_seconds,
+    ));
+    if !drained {
+        warn!(
+            " ** Gave up waiting on in-flight requests, {} running **",
+            state.shutdown.in_flight()
+        );
+    }
+    for handle in background {
+        if handle.join().is_err() {
+            error!("Background thread panicked during shutdown");
+        }
+    }
+    flush_views(state);
+    if let Err(e) = checkpoint(state) {
+        error!("Error checkpointing database: {}", e);
+    }
 }
 
 fn _handle_key(request: &rouille::Request, state: &State, key: &str) -> Result<rouille::Response> {
@@ -199,14 +534,62 @@ fn _handle_key(request: &rouille::Request, state: &State, key: &str) -> Result<r
     })
 }
 
+/// Label for the route a request matches, keeping
+/// metric cardinality independent of paste keys
+fn route_label(request: &rouille::Request) -> &'static str {
+    let url = request.url();
+    match url.as_str() {
+        "/" => "/",
+        "/favicon.ico" => "/favicon.ico",
+        "/robots.txt" => "/robots.txt",
+        "/status" => "/status",
+        "/status/ready" => "/status/ready",
+        "/metrics" => "/metrics",
+        "/new" => "/new",
+        "/verify" => "/verify",
+        "/.well-known/upaste-key" => "/.well-known/upaste-key",
+        u if u.starts_with("/raw/") => "/raw/{key}",
+        u if u.starts_with("/json/") => "/json/{key}",
+        u if u.starts_with("/share/") && u.ends_with("/revoke") => "/share/{key}/revoke",
+        u if u.starts_with("/share/") => "/share/{key}",
+        u if u.starts_with("/s/") && u.ends_with("/raw") => "/s/{token}/raw",
+        u if u.starts_with("/s/") && u.ends_with("/json") => "/s/{token}/json",
+        u if u.starts_with("/s/") => "/s/{token}",
+        u if u.starts_with("/static/") => "/static",
+        u if u[1..].contains('/') => "other",
+        _ => "/{key}",
+    }
+}
+
+/// Paste key a request refers to, if any
+fn request_paste_key(request: &rouille::Request, route: &str) -> Option<String> {
+    match route {
+        "/raw/{key}" | "/json/{key}" | "/{key}" => {
+            request.url().rsplit('/').next().map(String::from)
+        }
+        "/share/{key}" | "/share/{key}/revoke" => request.url().split('/').nth(2).map(String::from),
+        _ => None,
+    }
+}
+
 /// Route the request to appropriate handler
 fn route_request(request: &rouille::Request, state: State) -> Result<rouille::Response> {
     Ok(router!(request,
         (GET)   ["/"]               => { handlers::home(request, &state)? },
         (GET)   ["/favicon.ico"]    => { handlers::file("assets/favicon.ico")? },
         (GET)   ["/robots.txt"]     => { handlers::file("assets/robots.txt")? },
-        (GET)   ["/status"]         => { handlers::status()? },
+        (GET)   ["/status"]         => { handlers::status(&state)? },
+        (GET)   ["/status/ready"]   => { handlers::ready(&state)? },
+        (GET)   ["/metrics"]        => { handlers::metrics(&state)? },
         (POST)  ["/new"]            => { handlers::new_paste(request, &state)? },
+        (POST)  ["/verify"]         => { handlers::verify(request, &state)? },
+        (GET)   ["/.well-known/upaste-key"] => { handlers::public_key(&state)? },
+        (POST)  ["/share/{key}", key: String] => { handlers::share_paste(request, &state, &key)? },
+        (POST)  ["/share/{key}/revoke", key: String] => { handlers::revoke_share_links(request, &state, &key)? },
+        (GET)   ["/s/{token}", token: String] => { handlers::view_shared_paste(request, &state, &token)? },
+        (POST)  ["/s/{token}", token: String] => { handlers::view_shared_paste(request, &state, &token)? },
+        (GET)   ["/s/{token}/raw", token: String] => { handlers::view_shared_paste_raw(request, &state, &token)? },
+        (GET)   ["/s/{token}/json", token: String] => { handlers::view_shared_paste_json(request, &state, &token)? },
         (GET)   ["/raw/{key}", key: String] =>  { handlers::view_paste_raw(request, &state, &key)? },
         (GET)   ["/json/{key}", key: String] => { handlers::view_paste_json(request, &state, &key)? },
         (GET)   ["/{key}", key: String]     =>  { _handle_key(request, &state, &key)? },
@@ -222,3 +605,60 @@ fn route_request(request: &rouille::Request, state: State) -> Result<rouille::Re
         }
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::atomic::AtomicBool;
+
+    fn insert(state: &State, content: &str) -> String {
+        let new_paste = models::NewPaste {
+            content: content.into(),
+            content_type: "text".into(),
+            client_encrypted: false,
+            max_failed_attempts: None,
+            recipients: vec![],
+            private: false,
+        };
+        new_paste
+            .insert(&state.db, &state.config, None, None, &state.kdf_pool)
+            .unwrap()
+            .key
+    }
+
+    #[test]
+    fn stop_drains_requests_and_checkpoints() {
+        let dir = env::temp_dir().join(format!("upaste-stop-{}", std::process::id()));
+        let state = migrated_state(&dir, crate::Config::with_env(&[]));
+        insert(&state, "before");
+        let wal = dir.join("upaste-wal");
+        assert!(fs::metadata(&wal).unwrap().len() > 0);
+
+        let done = sync::Arc::new(AtomicBool::new(false));
+        let (started_tx, started) = sync::mpsc::channel();
+        let request = {
+            let (state, done) = (state.clone(), done.clone());
+            thread::spawn(move || {
+                let _in_flight = state.shutdown.track_request().unwrap();
+                started_tx.send(()).unwrap();
+                thread::sleep(time::Duration::from_millis(200));
+                let key = insert(&state, "in flight");
+                done.store(true, Ordering::SeqCst);
+                key
+            })
+        };
+        started.recv().unwrap();
+        stop(&state, vec![]);
+        assert!(
+            done.load(Ordering::SeqCst),
+            "stopped before the request finished"
+        );
+        assert!(state.shutdown.track_request().is_none());
+
+        let key = request.join().unwrap();
+        assert!(models::Paste::exists(&state.db.get().unwrap(), &key).unwrap());
+        assert_eq!(fs::metadata(&wal).unwrap().len(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+/*!
+Graceful shutdown
+ - Wait on SIGINT/SIGTERM
+ - Wake and stop background threads
+ - Track in-flight requests so they can be drained
+*/
+use std::mem;
+use std::ptr;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time;
+
+/// SIGINT and SIGTERM
+fn shutdown_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        set
+    }
+}
+
+/// Block SIGINT and SIGTERM on the calling thread and every thread it
+/// starts afterwards, so they're left for `wait_for_signal`. Has to be
+/// called before any other thread is started.
+pub fn block_signals() {
+    let set = shutdown_signals();
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+    }
+}
+
+/// Block until SIGINT or SIGTERM is received, returning the signal
+pub fn wait_for_signal() -> libc::c_int {
+    let set = shutdown_signals();
+    let mut signal = 0;
+    unsafe {
+        libc::sigwait(&set, &mut signal);
+    }
+    signal
+}
+
+#[derive(Default)]
+struct Status {
+    requested: bool,
+    in_flight: usize,
+}
+
+/// Shared shutdown state
+#[derive(Default)]
+pub struct Shutdown {
+    status: Mutex<Status>,
+    cvar: Condvar,
+}
+
+/// Marks a request as in-flight until dropped
+pub struct InFlight<'a> {
+    shutdown: &'a Shutdown,
+}
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.shutdown.lock().in_flight -= 1;
+        self.shutdown.cvar.notify_all();
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Status> {
+        self.status.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Ask all background threads to stop and turn away new requests
+    pub fn request(&self) {
+        self.lock().requested = true;
+        self.cvar.notify_all();
+    }
+
+    /// Sleep for `dur`, waking early if shutdown is requested.
+    /// Returns `true` if the caller should stop.
+    pub fn sleep(&self, dur: time::Duration) -> bool {
+        let (status, _) = self
+            .cvar
+            .wait_timeout_while(self.lock(), dur, |status| !status.requested)
+            .unwrap_or_else(|e| e.into_inner());
+        status.requested
+    }
+
+    /// Track a request until the returned guard is dropped,
+    /// `None` once shutdown is requested
+    pub fn track_request(&self) -> Option<InFlight<'_>> {
+        let mut status = self.lock();
+        if status.requested {
+            return None;
+        }
+        status.in_flight += 1;
+        Some(InFlight { shutdown: self })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.lock().in_flight
+    }
+
+    /// Wait up to `timeout` for in-flight requests to finish,
+    /// returning `false` if some are still running
+    pub fn drain(&self, timeout: time::Duration) -> bool {
+        let (status, _) = self
+            .cvar
+            .wait_timeout_while(self.lock(), timeout, |status| status.in_flight > 0)
+            .unwrap_or_else(|e| e.into_inner());
+        status.in_flight == 0
+    }
+}
@@ -0,0 +1,56 @@
+/*!
+Write-behind tracking of paste views
+
+Bumping `date_viewed` on every read would require a write transaction
+per request. Instead, views are recorded in memory and periodically
+flushed to the database in a single transaction.
+*/
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use rusqlite::Connection;
+
+use crate::errors::*;
+use crate::models::{Dt, Paste};
+
+/// Pending `date_viewed` updates, keyed by `Paste::key`
+#[derive(Default)]
+pub struct ViewTracker {
+    pending: Mutex<HashMap<String, Dt>>,
+}
+
+impl ViewTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, HashMap<String, Dt>>> {
+        self.pending.lock().map_err(|e| {
+            format_err!(ErrorKind::SyncPoison, "view tracker lock poisoned: {}", e).into()
+        })
+    }
+
+    /// Record that a paste was just viewed
+    pub fn record(&self, key: &str) -> Result<()> {
+        self.lock()?.insert(key.to_string(), Dt::now());
+        Ok(())
+    }
+
+    /// Write all pending views to the database, returning the number
+    /// of pastes touched. Views are kept for the next flush on failure.
+    pub fn flush(&self, conn: &mut Connection) -> Result<usize> {
+        let views = std::mem::take(&mut *self.lock()?);
+        if views.is_empty() {
+            return Ok(0);
+        }
+        if let Err(e) = Paste::touch_all(conn, &views) {
+            let mut pending = self.lock()?;
+            for (key, date) in views {
+                // anything recorded since is more recent
+                pending.entry(key).or_insert(date);
+            }
+            return Err(e);
+        }
+        Ok(views.len())
+    }
+}
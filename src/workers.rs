@@ -0,0 +1,136 @@
+/*!
+Bounded worker pool for key derivation
+
+Stretching user keys (Argon2id, or PBKDF2 for legacy pastes) is slow
+and memory hungry by design. Running it on request threads lets a burst
+of encrypted requests starve plain reads, so it runs here instead on a
+fixed number of threads with a bounded queue. Work is refused with
+`ErrorKind::Overloaded` once the queue is full.
+*/
+use std::panic;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::errors::*;
+use crate::logging;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct Counts {
+    queued: AtomicUsize,
+    active: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+pub struct WorkerPool {
+    name: &'static str,
+    workers: usize,
+    queue_size: usize,
+    sender: mpsc::SyncSender<Job>,
+    counts: Arc<Counts>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct PoolStats {
+    pub workers: usize,
+    pub queue_size: usize,
+    pub queued: usize,
+    pub active: usize,
+    pub rejected: u64,
+}
+
+impl WorkerPool {
+    /// Start `workers` threads sharing a queue of at most `queue_size` jobs
+    pub fn new(name: &'static str, workers: usize, queue_size: usize) -> Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let counts = Arc::new(Counts::default());
+        for i in 0..workers {
+            let receiver = receiver.clone();
+            let counts = counts.clone();
+            thread::Builder::new()
+                .name(format!("{}-{}", name, i))
+                .spawn(move || loop {
+                    let job = match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => return,
+                    };
+                    // the pool was dropped
+                    let job = match job {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    counts.queued.fetch_sub(1, Ordering::Relaxed);
+                    counts.active.fetch_add(1, Ordering::Relaxed);
+                    // a panicking job drops its result sender, failing `run`
+                    let _ = panic::catch_unwind(panic::AssertUnwindSafe(job));
+                    counts.active.fetch_sub(1, Ordering::Relaxed);
+                })
+                .chain_err(|| format!("Error starting {} worker", name))?;
+        }
+        Ok(Self {
+            name,
+            workers,
+            queue_size,
+            sender,
+            counts,
+        })
+    }
+
+    /// Run `f` on the pool, blocking until it's done. Anything `f` logs
+    /// is tagged with the calling thread's `request_id`.
+    pub fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let request_id = logging::current_request_id();
+        let job: Job = Box::new(move || {
+            let _scope = request_id.as_deref().map(logging::RequestScope::enter);
+            let _ = tx.send(f());
+        });
+        self.counts.queued.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.sender.try_send(job) {
+            self.counts.queued.fetch_sub(1, Ordering::Relaxed);
+            if let mpsc::TrySendError::Full(_) = e {
+                self.counts.rejected.fetch_add(1, Ordering::Relaxed);
+                bail_fmt!(ErrorKind::Overloaded, "{} queue is full", self.name);
+            }
+            bail!(format!("{} workers have stopped", self.name));
+        }
+        rx.recv()
+            .map_err(|_| format!("{} worker failed", self.name).into())
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            workers: self.workers,
+            queue_size: self.queue_size,
+            queued: self.counts.queued.load(Ordering::Relaxed),
+            active: self.counts.active.load(Ordering::Relaxed),
+            rejected: self.counts.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jobs_keep_request_id() {
+        let pool = WorkerPool::new("test", 1, 1).unwrap();
+        assert_eq!(pool.run(logging::current_request_id).unwrap(), None);
+        {
+            let _scope = logging::RequestScope::enter("abc123");
+            let id = pool.run(logging::current_request_id).unwrap();
+            assert_eq!(id.as_deref(), Some("abc123"));
+        }
+        // the worker doesn't hold on to it for the next job
+        assert_eq!(pool.run(logging::current_request_id).unwrap(), None);
+    }
+}